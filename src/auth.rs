@@ -0,0 +1,109 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+/// Keyword of the `auth-info-required` printer attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthInfo {
+    None,
+    Domain,
+    Username,
+    Password,
+    Negotiate,
+}
+
+impl AuthInfo {
+    pub fn keyword(&self) -> &'static str {
+        match *self {
+            AuthInfo::None => "none",
+            AuthInfo::Domain => "domain",
+            AuthInfo::Username => "username",
+            AuthInfo::Password => "password",
+            AuthInfo::Negotiate => "negotiate",
+        }
+    }
+}
+
+impl FromStr for AuthInfo {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<AuthInfo, ()> {
+        match s {
+            "none" => Ok(AuthInfo::None),
+            "domain" => Ok(AuthInfo::Domain),
+            "username" => Ok(AuthInfo::Username),
+            "password" => Ok(AuthInfo::Password),
+            "negotiate" => Ok(AuthInfo::Negotiate),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Encode a list of keywords as the `auth-info-required` attribute value.
+pub fn auth_info_value(auth_info: &[AuthInfo]) -> String {
+    if auth_info.is_empty() {
+        return String::from("none");
+    }
+    auth_info
+        .iter()
+        .map(AuthInfo::keyword)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Credentials supplied by the scheduler in the `AUTH_*` variables.
+/// The `Debug` output never contains the password or the negotiate token.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub domain: Option<String>,
+    /// Base64-encoded Kerberos credentials, `AUTH_NEGOTIATE`.
+    pub negotiate: Option<String>,
+    /// UID of the submitting user, `AUTH_UID`.
+    pub uid: Option<u32>,
+}
+
+impl Credentials {
+    /// Read `AUTH_USERNAME`, `AUTH_PASSWORD`, `AUTH_DOMAIN`, `AUTH_NEGOTIATE` and `AUTH_UID`.
+    /// Returns `None` if none of them is set.
+    pub fn from_vars(vars: &HashMap<String, String>) -> Option<Credentials> {
+        let get = |name: &str| vars.get(name).filter(|v| !v.is_empty()).cloned();
+
+        let credentials = Credentials {
+            username: get("AUTH_USERNAME"),
+            password: get("AUTH_PASSWORD"),
+            domain: get("AUTH_DOMAIN"),
+            negotiate: get("AUTH_NEGOTIATE"),
+            uid: get("AUTH_UID").and_then(|uid| uid.parse().ok()),
+        };
+
+        if credentials == Credentials::default() {
+            None
+        } else {
+            Some(credentials)
+        }
+    }
+
+    /// Fill the fields missing in `self` from `other`.
+    pub fn merge(self, other: Credentials) -> Credentials {
+        Credentials {
+            username: self.username.or(other.username),
+            password: self.password.or(other.password),
+            domain: self.domain.or(other.domain),
+            negotiate: self.negotiate.or(other.negotiate),
+            uid: self.uid.or(other.uid),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redacted = |value: &Option<String>| value.as_ref().map(|_| "***");
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &redacted(&self.password))
+            .field("domain", &self.domain)
+            .field("negotiate", &redacted(&self.negotiate))
+            .field("uid", &self.uid)
+            .finish()
+    }
+}
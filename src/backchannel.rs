@@ -0,0 +1,85 @@
+use std::{
+    io::{self, Read, Write},
+    os::unix::io::RawFd,
+    time::Duration,
+};
+
+use crate::{cupsbackend::current_back_channel, fd};
+
+/// Back-channel between the backend and the filters, used for data flowing
+/// from the printer (query responses, status). CUPS passes it as file descriptor 3.
+#[derive(Debug)]
+pub struct BackChannel {
+    fd: RawFd,
+}
+
+impl BackChannel {
+    pub const FD: RawFd = 3;
+
+    /// Open the back-channel of the job processed by the current thread. Returns `None`
+    /// if the job has none, e.g. when the backend was started from the command line.
+    pub fn open() -> Option<BackChannel> {
+        current_back_channel().map(|fd| BackChannel { fd })
+    }
+
+    /// Use an arbitrary descriptor as the back-channel. The descriptor is not closed on drop.
+    pub fn from_raw_fd(fd: RawFd) -> Option<BackChannel> {
+        if fd::is_open(fd) {
+            Some(BackChannel { fd })
+        } else {
+            None
+        }
+    }
+
+    /// Wait until data can be read, `None` waits forever.
+    pub fn poll_read(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        fd::poll(self.fd, libc::POLLIN, timeout)
+    }
+
+    /// Wait until data can be written, `None` waits forever.
+    pub fn poll_write(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        fd::poll(self.fd, libc::POLLOUT, timeout)
+    }
+
+    /// Read data from the back-channel, equivalent of `cupsBackChannelRead`.
+    /// Fails with `ErrorKind::TimedOut` if no data arrives within the timeout.
+    pub fn read_timeout(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        if !self.poll_read(timeout)? {
+            return Err(fd::timed_out("back-channel read"));
+        }
+        fd::read(self.fd, buf)
+    }
+
+    /// Write all data to the back-channel, equivalent of `cupsBackChannelWrite`.
+    /// The timeout applies to each individual write.
+    pub fn write_timeout(&self, buf: &[u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            if !self.poll_write(timeout)? {
+                return Err(fd::timed_out("back-channel write"));
+            }
+            match fd::write(self.fd, &buf[written..]) {
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl Read for BackChannel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_timeout(buf, None)
+    }
+}
+
+impl Write for BackChannel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_timeout(buf, None)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,495 @@
+//! Directory output backend: `file:///path/to/directory/[?options]`.
+//!
+//! Each job is written to a new file in the directory. The data goes to a
+//! temporary file first which is renamed into place once complete, so other
+//! processes watching the directory never see partial jobs.
+//!
+//! Supported URI options:
+//!
+//! * `template=name` - file name template, default `{time}-{job}-{user}-{title}`.
+//!   The placeholders are `{job}`, `{user}`, `{title}`, `{time}` (local time as
+//!   `YYYYMMDD-HHMMSS`) and `{timestamp}` (seconds since the epoch);
+//! * `collision=rename|overwrite|fail` - what to do if the file exists, default `rename`
+//!   which appends `-1`, `-2` and so on to the name;
+//! * `fsync=true|false` - flush the file and directory to disk, default true;
+//! * `metadata=true|false` - write a `<name>.json` sidecar with the job attributes
+//!   and options, default false. The collision policy applies to the job file and
+//!   its sidecar together.
+//!
+//! The job data is written once, the number of copies is only recorded in the metadata.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, Permissions},
+    io::{self, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, info};
+use tempfile::{Builder, NamedTempFile};
+use url::Url;
+
+use super::{json_string, parse_bool, scheme_device};
+use crate::{
+    device::{DeviceClass, DeviceInfo},
+    Backend, BackendData, BackendError, ExitCode, Result,
+};
+
+const DEFAULT_TEMPLATE: &str = "{time}-{job}-{user}-{title}";
+const PLACEHOLDERS: &[&str] = &["job", "user", "title", "time", "timestamp"];
+const MAX_NAME_LEN: usize = 200;
+const MAX_RENAME_ATTEMPTS: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Collision {
+    /// Append a numeric suffix to the name.
+    Rename,
+    Overwrite,
+    /// Fail the job.
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileOptions {
+    pub template: String,
+    pub collision: Collision,
+    pub fsync: bool,
+    pub metadata: bool,
+}
+
+impl Default for FileOptions {
+    fn default() -> FileOptions {
+        FileOptions {
+            template: DEFAULT_TEMPLATE.to_owned(),
+            collision: Collision::Rename,
+            fsync: true,
+            metadata: false,
+        }
+    }
+}
+
+impl FileOptions {
+    pub fn from_uri(uri: &Url) -> Result<FileOptions> {
+        let mut options = FileOptions::default();
+        for (name, value) in uri.query_pairs() {
+            let invalid = || BackendError::InvalidOption {
+                name: name.to_string(),
+                value: value.to_string(),
+            };
+            match name.as_ref() {
+                "template" => {
+                    if value.is_empty() || value.contains('/') || !is_valid_template(&value) {
+                        return Err(invalid());
+                    }
+                    options.template = value.to_string();
+                }
+                "collision" => {
+                    options.collision = match value.as_ref() {
+                        "rename" => Collision::Rename,
+                        "overwrite" => Collision::Overwrite,
+                        "fail" => Collision::Fail,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "fsync" => options.fsync = parse_bool(&value).ok_or_else(invalid)?,
+                "metadata" => options.metadata = parse_bool(&value).ok_or_else(invalid)?,
+                _ => debug!("Ignoring unknown URI option {}", name),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Split a template into literal text and placeholder names.
+fn template_parts(template: &str) -> Option<Vec<(bool, &str)>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}')? + start;
+        parts.push((false, &rest[..start]));
+        parts.push((true, &rest[start + 1..end]));
+        rest = &rest[end + 1..];
+    }
+    parts.push((false, rest));
+    Some(parts)
+}
+
+fn is_valid_template(template: &str) -> bool {
+    template_parts(template)
+        .map(|parts| {
+            parts
+                .iter()
+                .all(|(placeholder, name)| !placeholder || PLACEHOLDERS.contains(name))
+        })
+        .unwrap_or(false)
+}
+
+/// Replace characters which are not safe in a file name.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c == '/' || c.is_control() { '_' } else { c })
+        .collect()
+}
+
+/// Local time as `YYYYMMDD-HHMMSS`.
+fn local_time(secs: u64) -> String {
+    let time = secs as libc::time_t;
+    // SAFETY: localtime_r only writes to the provided tm structure
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return secs.to_string();
+    }
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+/// Expand the file name template for a job created at `time`.
+pub fn expand_template(template: &str, data: &BackendData, time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let mut name = String::new();
+    for (placeholder, text) in template_parts(template).unwrap_or_default() {
+        if !placeholder {
+            name.push_str(text);
+            continue;
+        }
+        let value = match text {
+            "job" => data.job_id.to_string(),
+            "user" => data.user_name.clone(),
+            "title" => data.title.clone(),
+            "time" => local_time(secs),
+            "timestamp" => secs.to_string(),
+            _ => String::new(),
+        };
+        name.push_str(&sanitize(&value));
+    }
+
+    let mut name = sanitize(name.trim());
+    if name.len() > MAX_NAME_LEN {
+        let mut end = MAX_NAME_LEN;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+    match name.as_str() {
+        "" | "." | ".." => format!("job-{}", data.job_id),
+        _ => name,
+    }
+}
+
+/// Name with a numeric suffix inserted before the extension, if any.
+fn numbered_name(name: &str, n: u32) -> String {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{}-{}{}", &name[..dot], n, &name[dot..]),
+        _ => format!("{}-{}", name, n),
+    }
+}
+
+/// Sidecar metadata for a job written to `file_name`.
+pub fn metadata_json(data: &BackendData, file_name: &str, time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let options = data
+        .options
+        .iter()
+        .collect::<BTreeMap<_, _>>()
+        .into_iter()
+        .map(|(name, value)| format!("    {}: {}", json_string(name), json_string(value)))
+        .collect::<Vec<_>>();
+    let content_type = data
+        .environment
+        .content_type
+        .as_deref()
+        .map(json_string)
+        .unwrap_or_else(|| "null".to_owned());
+
+    format!(
+        "{{\n  \"job-id\": {},\n  \"user\": {},\n  \"title\": {},\n  \"copies\": {},\n  \
+         \"content-type\": {},\n  \"file\": {},\n  \"time\": {},\n  \"options\": {{{}}}\n}}\n",
+        data.job_id,
+        json_string(&data.user_name),
+        json_string(&data.title),
+        data.copies,
+        content_type,
+        json_string(file_name),
+        secs,
+        if options.is_empty() {
+            String::new()
+        } else {
+            format!("\n{}\n  ", options.join(",\n"))
+        }
+    )
+}
+
+/// Writes each job into a file in a directory.
+#[derive(Default)]
+pub struct FileBackend;
+
+impl FileBackend {
+    pub fn new() -> FileBackend {
+        FileBackend
+    }
+
+    /// Write a temporary file in the directory, to be persisted under its final name.
+    fn temp_file<F>(dir: &Path, options: &FileOptions, write: F) -> io::Result<NamedTempFile>
+    where
+        F: FnOnce(&mut File) -> io::Result<()>,
+    {
+        let mut temp = Builder::new()
+            .prefix(".")
+            .suffix(".part")
+            .tempfile_in(dir)?;
+        // temporary files are private, the output is meant to be shared
+        temp.as_file()
+            .set_permissions(Permissions::from_mode(0o644))?;
+        write(temp.as_file_mut())?;
+        if options.fsync {
+            temp.as_file().sync_all()?;
+        }
+        Ok(temp)
+    }
+
+    /// Move a completed job file into place, returning the final name. With `metadata`
+    /// the `<name>.json` sidecar for the chosen name is created first, so the collision
+    /// policy covers both files and a job never replaces another job's sidecar.
+    fn persist(
+        mut temp: NamedTempFile,
+        dir: &Path,
+        name: &str,
+        options: &FileOptions,
+        metadata: impl Fn(&str) -> String,
+    ) -> io::Result<String> {
+        let sidecar = |name: &str| {
+            let json = metadata(name);
+            FileBackend::temp_file(dir, options, |file| file.write_all(json.as_bytes()))
+        };
+        let sidecar_name = |name: &str| format!("{}.json", name);
+
+        if options.collision == Collision::Overwrite {
+            temp.persist(dir.join(name)).map_err(|e| e.error)?;
+            if options.metadata {
+                sidecar(name)?
+                    .persist(dir.join(sidecar_name(name)))
+                    .map_err(|e| e.error)?;
+            }
+            return Ok(name.to_owned());
+        }
+
+        for n in 0..MAX_RENAME_ATTEMPTS {
+            let candidate = if n == 0 {
+                name.to_owned()
+            } else {
+                numbered_name(name, n)
+            };
+            let error = if options.metadata {
+                match sidecar(&candidate)?.persist_noclobber(dir.join(sidecar_name(&candidate))) {
+                    Ok(_) => None,
+                    Err(e) => Some(e.error),
+                }
+            } else {
+                None
+            };
+            let error = match error {
+                Some(error) => error,
+                None => match temp.persist_noclobber(dir.join(&candidate)) {
+                    Ok(_) => return Ok(candidate),
+                    Err(e) => {
+                        if options.metadata {
+                            // release the name reserved with the sidecar
+                            let _ = fs::remove_file(dir.join(sidecar_name(&candidate)));
+                        }
+                        temp = e.file;
+                        e.error
+                    }
+                },
+            };
+            if error.kind() != io::ErrorKind::AlreadyExists || options.collision == Collision::Fail
+            {
+                return Err(error);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("too many files named {}", name),
+        ))
+    }
+
+    fn write_job(dir: &Path, data: &BackendData, options: &FileOptions) -> io::Result<String> {
+        let now = SystemTime::now();
+        let name = expand_template(&options.template, data, now);
+
+        let temp = FileBackend::temp_file(dir, options, |file| {
+            let mut source = data.job_source.reader()?;
+            io::copy(&mut source, file)?;
+            file.flush()
+        })?;
+        let name = FileBackend::persist(temp, dir, &name, options, |name| {
+            metadata_json(data, name, now)
+        })?;
+        info!("Wrote job to {}", dir.join(&name).display());
+
+        if options.fsync {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(name)
+    }
+}
+
+impl Backend for FileBackend {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn description(&self) -> &str {
+        "Directory Output"
+    }
+
+    fn discover(&self) -> Vec<DeviceInfo> {
+        vec![scheme_device(
+            DeviceClass::File,
+            self.name(),
+            self.description(),
+        )]
+    }
+
+    fn submit_job(&mut self, data: &BackendData) -> Result<ExitCode> {
+        let dir: PathBuf = data
+            .printer_uri
+            .to_file_path()
+            .map_err(|_| BackendError::NoUri)?;
+        let options = FileOptions::from_uri(&data.printer_uri)?;
+
+        if !fs::metadata(&dir).map(|m| m.is_dir()).unwrap_or(false) {
+            error!("{} is not a directory", dir.display());
+            return Ok(ExitCode::StopQueue);
+        }
+
+        match FileBackend::write_job(&dir, data, &options) {
+            Ok(_) => Ok(ExitCode::Success),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                error!("Unable to write job: {}", e);
+                Ok(ExitCode::CancelJob)
+            }
+            Err(e) => {
+                error!("Unable to write job to {}: {}", dir.display(), e);
+                Ok(ExitCode::ErrorPolicy)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn submit(dir: &Path, query: &str) -> ExitCode {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"job data").unwrap();
+        let uri = format!("file://{}/?{}", dir.display(), query);
+        let env = HashMap::from([(String::from("DEVICE_URI"), uri)]);
+        let args = [
+            "file",
+            "9",
+            "alice",
+            "report",
+            "1",
+            "",
+            file.path().to_str().unwrap(),
+        ]
+        .map(String::from);
+        let data = BackendData::from_args(&args, &env).unwrap();
+        FileBackend::new().submit_job(&data).unwrap()
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn renamed_job_keeps_own_sidecar() {
+        let dir = TempDir::new().unwrap();
+        for _ in 0..2 {
+            let code = submit(dir.path(), "template=foo&metadata=true&fsync=false");
+            assert_eq!(code, ExitCode::Success);
+        }
+        assert_eq!(
+            names(dir.path()),
+            ["foo", "foo-1", "foo-1.json", "foo.json"]
+        );
+        let json = fs::read_to_string(dir.path().join("foo-1.json")).unwrap();
+        assert!(json.contains("\"file\": \"foo-1\""));
+        assert_eq!(fs::read(dir.path().join("foo-1")).unwrap(), b"job data");
+    }
+
+    #[test]
+    fn existing_sidecar_reserves_name() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("foo.json"), "{}").unwrap();
+
+        let code = submit(dir.path(), "template=foo&metadata=true&fsync=false");
+        assert_eq!(code, ExitCode::Success);
+        assert_eq!(names(dir.path()), ["foo-1", "foo-1.json", "foo.json"]);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("foo.json")).unwrap(),
+            "{}"
+        );
+
+        let code = submit(
+            dir.path(),
+            "template=foo&metadata=true&collision=fail&fsync=false",
+        );
+        assert_eq!(code, ExitCode::CancelJob);
+        assert_eq!(names(dir.path()), ["foo-1", "foo-1.json", "foo.json"]);
+    }
+
+    #[test]
+    fn existing_job_releases_sidecar() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("foo"), "old").unwrap();
+
+        let code = submit(dir.path(), "template=foo&metadata=true&fsync=false");
+        assert_eq!(code, ExitCode::Success);
+        assert_eq!(names(dir.path()), ["foo", "foo-1", "foo-1.json"]);
+    }
+
+    #[test]
+    fn overwrite() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("foo"), "old").unwrap();
+        fs::write(dir.path().join("foo.json"), "{}").unwrap();
+
+        let code = submit(dir.path(), "template=foo&metadata=true&collision=overwrite");
+        assert_eq!(code, ExitCode::Success);
+        assert_eq!(names(dir.path()), ["foo", "foo.json"]);
+        assert_eq!(fs::read(dir.path().join("foo")).unwrap(), b"job data");
+        assert_ne!(
+            fs::read_to_string(dir.path().join("foo.json")).unwrap(),
+            "{}"
+        );
+    }
+}
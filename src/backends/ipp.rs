@@ -0,0 +1,1002 @@
+//! IPP backend: `ipp://host[:port]/path` and `ipps://host[:port]/path`.
+//!
+//! Supported URI options:
+//!
+//! * `waitjob=true|false` - monitor the job on the printer until it is completed, default true;
+//! * `contimeout=seconds` - how long to retry connecting before giving up, default one week;
+//! * `verify=true|false` - verify the certificate of `ipps` printers, default true;
+//! * `plainauth=true|false` - allow sending credentials over an unencrypted `ipp`
+//!   connection, default false.
+//!
+//! Printers rarely have certificates signed by a public authority, `verify=false`
+//! accepts self-signed ones. Credentials from `AUTH_USERNAME` and `AUTH_PASSWORD`
+//! are only sent once the printer asks for them, and only over a verified `ipps`
+//! connection unless `plainauth` is set. They are never sent to an unverified printer.
+
+use std::{
+    io::{self, Read},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{debug, error, info, warn};
+use url::Url;
+
+use super::{
+    connect_with_retry, parse_bool, scheme_device, sleep_unless_cancelled, DEFAULT_CONTIMEOUT,
+};
+use crate::{
+    auth::{AuthInfo, Credentials},
+    device::{DeviceClass, DeviceInfo},
+    format::DocumentFormat,
+    http::{Body, Client},
+    ipp::{group, status, Attribute, IppError, IppMessage, Operation, Value},
+    is_cancelled,
+    status::StatusWriter,
+    Backend, BackendData, BackendError, ExitCode, JobSource, Result,
+};
+
+const DEFAULT_PORT: u16 = 631;
+const MONITOR_INTERVAL: Duration = Duration::from_secs(2);
+
+const JOB_STATE_CANCELED: i32 = 7;
+const JOB_STATE_ABORTED: i32 = 8;
+const JOB_STATE_COMPLETED: i32 = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IppOptions {
+    pub wait_job: bool,
+    pub contimeout: Duration,
+    pub verify: bool,
+    pub plain_auth: bool,
+}
+
+impl Default for IppOptions {
+    fn default() -> IppOptions {
+        IppOptions {
+            wait_job: true,
+            contimeout: DEFAULT_CONTIMEOUT,
+            verify: true,
+            plain_auth: false,
+        }
+    }
+}
+
+impl IppOptions {
+    pub fn from_uri(uri: &Url) -> Result<IppOptions> {
+        let mut options = IppOptions::default();
+        for (name, value) in uri.query_pairs() {
+            let invalid = || BackendError::InvalidOption {
+                name: name.to_string(),
+                value: value.to_string(),
+            };
+            match name.as_ref() {
+                "waitjob" => options.wait_job = parse_bool(&value).ok_or_else(invalid)?,
+                "verify" => options.verify = parse_bool(&value).ok_or_else(invalid)?,
+                "plainauth" => options.plain_auth = parse_bool(&value).ok_or_else(invalid)?,
+                "contimeout" => {
+                    let secs = value.parse::<u64>().map_err(|_| invalid())?;
+                    options.contimeout = Duration::from_secs(secs);
+                }
+                _ => debug!("Ignoring unknown URI option {}", name),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Map an IPP status code onto the backend exit code.
+pub fn status_exit_code(code: u16) -> ExitCode {
+    match code {
+        _ if status::is_successful(code) => ExitCode::Success,
+        status::CLIENT_ERROR_FORBIDDEN
+        | status::CLIENT_ERROR_NOT_AUTHENTICATED
+        | status::CLIENT_ERROR_NOT_AUTHORIZED => ExitCode::AuthRequired,
+        status::CLIENT_ERROR_TIMEOUT => ExitCode::ErrorPolicy,
+        status::CLIENT_ERROR_NOT_FOUND => ExitCode::StopQueue,
+        0x0400..=0x04ff => ExitCode::CancelJob,
+        _ => ExitCode::ErrorPolicy,
+    }
+}
+
+/// Job template attributes for the typed job options that are set.
+pub fn job_attributes(data: &BackendData) -> Result<Vec<Attribute>> {
+    let mut attributes = Vec::new();
+
+    if data.copies > 1 {
+        attributes.push(Attribute::new(
+            "copies",
+            Value::Integer(data.copies.min(i32::MAX as u32) as i32),
+        ));
+    }
+    if let Some(media) = data.media()? {
+        attributes.push(Attribute::new("media", Value::Keyword(media.name)));
+    }
+    if let Some(sides) = data.sides()? {
+        attributes.push(Attribute::new(
+            "sides",
+            Value::Keyword(sides.keyword().to_owned()),
+        ));
+    }
+    if let Some(mode) = data.print_color_mode()? {
+        attributes.push(Attribute::new(
+            "print-color-mode",
+            Value::Keyword(mode.keyword().to_owned()),
+        ));
+    }
+    if let Some(quality) = data.print_quality()? {
+        attributes.push(Attribute::new("print-quality", Value::Enum(quality as i32)));
+    }
+    if let Some(number_up) = data.number_up()? {
+        attributes.push(Attribute::new(
+            "number-up",
+            Value::Integer(number_up as i32),
+        ));
+    }
+    if let Some(ranges) = data.page_ranges()? {
+        let clamp = |page: u32| page.min(i32::MAX as u32) as i32;
+        let values = ranges
+            .iter()
+            .map(|r| Value::RangeOfInteger(clamp(*r.start()), clamp(*r.end())))
+            .collect();
+        attributes.push(Attribute::with_values("page-ranges", values));
+    }
+    if let Some(orientation) = data.orientation_requested()? {
+        attributes.push(Attribute::new(
+            "orientation-requested",
+            Value::Enum(orientation as i32),
+        ));
+    }
+    if let Some(priority) = data.job_priority()? {
+        attributes.push(Attribute::new(
+            "job-priority",
+            Value::Integer(priority as i32),
+        ));
+    }
+    if let Some(hold) = data.job_hold_until()? {
+        attributes.push(Attribute::new(
+            "job-hold-until",
+            Value::Keyword(hold.keyword()),
+        ));
+    }
+
+    Ok(attributes)
+}
+
+/// Job ID assigned by the printer, a response without one is a protocol error.
+fn job_id(response: &IppMessage) -> Option<i32> {
+    let job_id = response
+        .group_attribute(group::JOB, "job-id")
+        .and_then(Attribute::value)
+        .and_then(Value::as_i32)
+        .filter(|&job_id| job_id > 0);
+    if job_id.is_none() {
+        error!("Printer did not return a job ID");
+    }
+    job_id
+}
+
+enum RequestError {
+    IOError(io::Error),
+    Ipp(IppError),
+    Http(u16),
+}
+
+/// Prints to IPP printers, monitoring the job until it is completed.
+#[derive(Default)]
+pub struct IppBackend {
+    client: Client,
+    http_url: Option<Url>,
+    printer_uri: String,
+    user_name: String,
+    /// Authorization header, `None` if there are no credentials or they may not be sent.
+    authorization: Option<String>,
+    /// Set once the printer has asked for credentials, they are then sent with every request.
+    authenticated: bool,
+    /// What to change so that credentials can be sent, if they may not be.
+    credentials_blocked: Option<&'static str>,
+    request_id: u32,
+    /// Job ID assigned by the printer.
+    remote_job: Option<i32>,
+}
+
+impl IppBackend {
+    pub fn new() -> IppBackend {
+        IppBackend::default()
+    }
+
+    fn setup(&mut self, data: &BackendData, options: &IppOptions) -> Result<()> {
+        let uri = data.printer_uri.without_credentials();
+        let scheme = match uri.scheme() {
+            "ipp" => "http",
+            "ipps" => "https",
+            _ => return Err(BackendError::NoUri),
+        };
+        let host = uri.host_str().ok_or(BackendError::NoUri)?;
+        let port = uri.port().unwrap_or(DEFAULT_PORT);
+        let path = if uri.path().is_empty() {
+            "/"
+        } else {
+            uri.path()
+        };
+
+        let http_url = format!("{}://{}:{}{}", scheme, host, port, path);
+        self.http_url = Some(Url::parse(&http_url).map_err(|_| BackendError::NoUri)?);
+        self.printer_uri = format!("{}://{}:{}{}", uri.scheme(), host, port, path);
+        self.client.accept_invalid_certs = scheme == "https" && !options.verify;
+        self.user_name = data.user_name.clone();
+        self.credentials_blocked = if self.client.accept_invalid_certs {
+            Some("enable certificate verification")
+        } else if scheme == "http" && !options.plain_auth {
+            Some("use ipps or set plainauth=true")
+        } else {
+            None
+        };
+        self.authorization = match data.credentials {
+            _ if self.credentials_blocked.is_some() => None,
+            Some(Credentials {
+                username: Some(ref username),
+                password: Some(ref password),
+                ..
+            }) => Some(format!(
+                "Basic {}",
+                STANDARD.encode(format!("{}:{}", username, password))
+            )),
+            _ => None,
+        };
+        Ok(())
+    }
+
+    fn new_request(&mut self, operation: Operation) -> IppMessage {
+        self.request_id += 1;
+        let mut request = IppMessage::request(operation, self.request_id);
+        request.add(
+            group::OPERATION,
+            Attribute::new("printer-uri", Value::Uri(self.printer_uri.clone())),
+        );
+        request.add(
+            group::OPERATION,
+            Attribute::new("requesting-user-name", Value::Name(self.user_name.clone())),
+        );
+        request
+    }
+
+    /// Send a request, repeating it with credentials if the printer asks for them.
+    /// The document is read again for the repeated request.
+    fn exchange(
+        &mut self,
+        request: &IppMessage,
+        document: Option<&JobSource>,
+    ) -> std::result::Result<IppMessage, RequestError> {
+        match self.send(request, document) {
+            Err(RequestError::Http(401)) if !self.authenticated && self.authorization.is_some() => {
+                debug!("Printer requires authentication, sending credentials");
+                self.authenticated = true;
+                self.send(request, document)
+            }
+            result => result,
+        }
+    }
+
+    /// Send a request with optional document data and decode the response.
+    /// Document data of unknown size is sent with chunked encoding.
+    fn send(
+        &self,
+        request: &IppMessage,
+        document: Option<&JobSource>,
+    ) -> std::result::Result<IppMessage, RequestError> {
+        let url = self.http_url.as_ref().ok_or(RequestError::Http(0))?;
+        let encoded = request.encode();
+
+        let mut headers = Vec::new();
+        match self.authorization {
+            Some(ref authorization) if self.authenticated => {
+                headers.push(("Authorization", authorization.clone()))
+            }
+            _ => {}
+        }
+
+        let mut head = io::Cursor::new(encoded);
+        let length = head.get_ref().len() as u64;
+        let response = match document {
+            Some(source) => {
+                let size = source.size().map_err(RequestError::IOError)?;
+                let reader = source.reader().map_err(RequestError::IOError)?;
+                let mut reader = head.chain(reader);
+                let body = Body {
+                    content_type: "application/ipp",
+                    reader: &mut reader,
+                    length: size.map(|size| length + size),
+                };
+                self.client.send("POST", url, &headers, Some(body))
+            }
+            None => {
+                let body = Body {
+                    content_type: "application/ipp",
+                    reader: &mut head,
+                    length: Some(length),
+                };
+                self.client.send("POST", url, &headers, Some(body))
+            }
+        }
+        .map_err(RequestError::IOError)?;
+
+        if !response.is_success() {
+            return Err(RequestError::Http(response.status));
+        }
+        IppMessage::decode(&response.body)
+            .map(|(message, _)| message)
+            .map_err(RequestError::Ipp)
+    }
+
+    /// Send a request and check the IPP status, the error is the exit code to return.
+    fn call(
+        &mut self,
+        request: &IppMessage,
+        document: Option<&JobSource>,
+    ) -> Result<std::result::Result<IppMessage, ExitCode>> {
+        let result = self.exchange(request, document);
+        self.check(result)
+    }
+
+    /// Exit code for a printer asking for credentials which were rejected or not sent.
+    fn auth_required(&self) -> Result<std::result::Result<IppMessage, ExitCode>> {
+        match self.credentials_blocked {
+            Some(fix) => {
+                error!("Printer requires authentication, {}", fix);
+                Ok(Err(ExitCode::StopQueue))
+            }
+            None => Err(BackendError::AuthRequired(vec![
+                AuthInfo::Username,
+                AuthInfo::Password,
+            ])),
+        }
+    }
+
+    fn check(
+        &self,
+        result: std::result::Result<IppMessage, RequestError>,
+    ) -> Result<std::result::Result<IppMessage, ExitCode>> {
+        let response = match result {
+            Ok(response) => response,
+            Err(RequestError::Http(401)) => return self.auth_required(),
+            Err(RequestError::Http(code)) => {
+                error!("Printer returned HTTP status {}", code);
+                return Ok(Err(ExitCode::ErrorPolicy));
+            }
+            Err(RequestError::Ipp(e)) => {
+                error!("Invalid IPP response: {}", e);
+                return Ok(Err(ExitCode::ErrorPolicy));
+            }
+            Err(RequestError::IOError(e)) => {
+                error!("Unable to communicate with printer: {}", e);
+                return Ok(Err(ExitCode::ErrorPolicy));
+            }
+        };
+
+        if status::is_successful(response.code) {
+            return Ok(Ok(response));
+        }
+
+        let message = response
+            .attribute("status-message")
+            .and_then(Attribute::value)
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        error!(
+            "Printer returned IPP status 0x{:04x} {}",
+            response.code, message
+        );
+        match status_exit_code(response.code) {
+            ExitCode::AuthRequired => self.auth_required(),
+            code => Ok(Err(code)),
+        }
+    }
+
+    /// Query the printer, retrying until `contimeout` expires if it cannot be reached.
+    fn get_printer_attributes<W: io::Write>(
+        &mut self,
+        options: &IppOptions,
+        status: &mut StatusWriter<W>,
+    ) -> Result<std::result::Result<IppMessage, ExitCode>> {
+        let mut request = self.new_request(Operation::GetPrinterAttributes);
+        request.add(
+            group::OPERATION,
+            Attribute::with_values(
+                "requested-attributes",
+                ["operations-supported", "document-format-supported"]
+                    .iter()
+                    .map(|name| Value::Keyword((*name).to_owned()))
+                    .collect(),
+            ),
+        );
+
+        // the client has its own timeout, the retry only needs to know about I/O errors
+        let result = connect_with_retry(options.contimeout, status, |_| {
+            match self.exchange(&request, None) {
+                Err(RequestError::IOError(e)) => Err(e),
+                result => Ok(result),
+            }
+        })?;
+        match result {
+            Some(result) => self.check(result),
+            None => Ok(Err(ExitCode::ErrorPolicy)),
+        }
+    }
+
+    fn monitor<W: io::Write>(
+        &mut self,
+        job_id: i32,
+        status: &mut StatusWriter<W>,
+    ) -> Result<ExitCode> {
+        let mut last_state = 0;
+        while sleep_unless_cancelled(MONITOR_INTERVAL) {
+            let mut request = self.new_request(Operation::GetJobAttributes);
+            request.add(
+                group::OPERATION,
+                Attribute::new("job-id", Value::Integer(job_id)),
+            );
+            request.add(
+                group::OPERATION,
+                Attribute::with_values(
+                    "requested-attributes",
+                    [
+                        "job-state",
+                        "job-state-reasons",
+                        "job-impressions-completed",
+                    ]
+                    .iter()
+                    .map(|name| Value::Keyword((*name).to_owned()))
+                    .collect(),
+                ),
+            );
+
+            let response = match self.exchange(&request, None) {
+                Ok(response) if status::is_successful(response.code) => response,
+                _ => {
+                    // the printer may have already purged the job
+                    warn!("Unable to get job attributes, assuming the job is completed");
+                    return Ok(ExitCode::Success);
+                }
+            };
+
+            let int = |name: &str| {
+                response
+                    .group_attribute(group::JOB, name)
+                    .and_then(Attribute::value)
+                    .and_then(Value::as_i32)
+            };
+            let state = int("job-state").unwrap_or_default();
+            if state != last_state {
+                debug!("Job {} state is {}", job_id, state);
+                last_state = state;
+            }
+
+            match state {
+                JOB_STATE_COMPLETED => {
+                    if let Some(impressions) = int("job-impressions-completed") {
+                        status.total_pages(impressions.max(0) as u32)?;
+                    }
+                    info!("Job completed");
+                    return Ok(ExitCode::Success);
+                }
+                JOB_STATE_CANCELED => {
+                    error!("Job was canceled at the printer");
+                    return Ok(ExitCode::CancelJob);
+                }
+                JOB_STATE_ABORTED => {
+                    error!("Job was aborted by the printer");
+                    return Ok(ExitCode::ErrorPolicy);
+                }
+                _ => {}
+            }
+        }
+        Ok(ExitCode::Success)
+    }
+}
+
+impl Backend for IppBackend {
+    fn name(&self) -> &str {
+        "ipp"
+    }
+
+    fn description(&self) -> &str {
+        "Internet Printing Protocol (ipp)"
+    }
+
+    fn discover(&self) -> Vec<DeviceInfo> {
+        ["ipp", "ipps"]
+            .iter()
+            .map(|scheme| {
+                let info = format!("Internet Printing Protocol ({})", scheme);
+                scheme_device(DeviceClass::Network, scheme, &info)
+            })
+            .collect()
+    }
+
+    fn submit_job(&mut self, data: &BackendData) -> Result<ExitCode> {
+        let options = IppOptions::from_uri(&data.printer_uri)?;
+        self.setup(data, &options)?;
+        let mut status = StatusWriter::stderr().with_job_id(data.job_id);
+        let job_attributes = job_attributes(data)?;
+
+        info!("Connecting to {}", self.printer_uri);
+        let printer = match self.get_printer_attributes(&options, &mut status)? {
+            Ok(printer) => printer,
+            Err(code) => return Ok(code),
+        };
+
+        let supports = |op: Operation| {
+            printer
+                .attribute("operations-supported")
+                .map(|a| a.values.iter().any(|v| v.as_i32() == Some(op as i32)))
+                .unwrap_or(false)
+        };
+        let detected = data.document_format()?.map(DocumentFormat::mime_type);
+        let format = data
+            .environment
+            .final_content_type
+            .as_deref()
+            .into_iter()
+            .chain(detected)
+            .find(|format| {
+                printer
+                    .attribute("document-format-supported")
+                    .map(|a| {
+                        a.values
+                            .iter()
+                            .filter_map(Value::as_str)
+                            .any(|v| v.eq_ignore_ascii_case(format))
+                    })
+                    .unwrap_or(false)
+            })
+            .unwrap_or("application/octet-stream")
+            .to_owned();
+
+        let operation_attributes = |request: &mut IppMessage| {
+            request.add(
+                group::OPERATION,
+                Attribute::new("job-name", Value::Name(data.title.clone())),
+            );
+            for attribute in &job_attributes {
+                request.add(group::JOB, attribute.clone());
+            }
+        };
+
+        if supports(Operation::ValidateJob) {
+            let mut request = self.new_request(Operation::ValidateJob);
+            operation_attributes(&mut request);
+            request.add(
+                group::OPERATION,
+                Attribute::new("document-format", Value::MimeMediaType(format.clone())),
+            );
+            if let Err(code) = self.call(&request, None)? {
+                return Ok(code);
+            }
+        }
+
+        if self.authorization.is_some() && !self.authenticated {
+            // the document is sent again if the printer asks for credentials
+            data.job_source.path()?;
+        }
+        let document = Some(&data.job_source);
+
+        let job_id = if supports(Operation::CreateJob) && supports(Operation::SendDocument) {
+            let mut request = self.new_request(Operation::CreateJob);
+            operation_attributes(&mut request);
+            let response = match self.call(&request, None)? {
+                Ok(response) => response,
+                Err(code) => return Ok(code),
+            };
+            let job_id = match job_id(&response) {
+                Some(job_id) => job_id,
+                None => return Ok(ExitCode::ErrorPolicy),
+            };
+            self.remote_job = Some(job_id);
+
+            let mut request = self.new_request(Operation::SendDocument);
+            request.add(
+                group::OPERATION,
+                Attribute::new("job-id", Value::Integer(job_id)),
+            );
+            request.add(
+                group::OPERATION,
+                Attribute::new("document-format", Value::MimeMediaType(format)),
+            );
+            request.add(
+                group::OPERATION,
+                Attribute::new("last-document", Value::Boolean(true)),
+            );
+            if let Err(code) = self.call(&request, document)? {
+                return Ok(code);
+            }
+            job_id
+        } else {
+            let mut request = self.new_request(Operation::PrintJob);
+            operation_attributes(&mut request);
+            request.add(
+                group::OPERATION,
+                Attribute::new("document-format", Value::MimeMediaType(format)),
+            );
+            let response = match self.call(&request, document)? {
+                Ok(response) => response,
+                Err(code) => return Ok(code),
+            };
+            let job_id = match job_id(&response) {
+                Some(job_id) => job_id,
+                None => return Ok(ExitCode::ErrorPolicy),
+            };
+            self.remote_job = Some(job_id);
+            job_id
+        };
+        info!("Printer accepted job {}", job_id);
+
+        if !options.wait_job || is_cancelled() {
+            return Ok(ExitCode::Success);
+        }
+        status.info("Waiting for job to complete")?;
+        self.monitor(job_id, &mut status)
+    }
+
+    fn cancel_job(&mut self, _data: &BackendData) -> Result<ExitCode> {
+        if let Some(job_id) = self.remote_job.take() {
+            info!("Canceling job {} on the printer", job_id);
+            let mut request = self.new_request(Operation::CancelJob);
+            request.add(
+                group::OPERATION,
+                Attribute::new("job-id", Value::Integer(job_id)),
+            );
+            if let Err(code) = self.call(&request, None)? {
+                warn!("Unable to cancel job {} ({:?})", job_id, code);
+            }
+        }
+        Ok(ExitCode::CancelJob)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    /// Request received by the fake printer.
+    struct Received {
+        operation: u16,
+        authorization: Option<String>,
+        document: Vec<u8>,
+    }
+
+    fn read_request(reader: &mut impl BufRead) -> (Option<String>, Vec<u8>) {
+        let mut authorization = None;
+        let mut length = None;
+        let mut chunked = false;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap_or((line, ""));
+            match name.to_ascii_lowercase().as_str() {
+                "authorization" => authorization = Some(value.trim().to_owned()),
+                "content-length" => length = value.trim().parse::<usize>().ok(),
+                "transfer-encoding" => chunked = true,
+                _ => {}
+            }
+        }
+
+        let mut body = Vec::new();
+        if chunked {
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let size = usize::from_str_radix(line.trim(), 16).unwrap();
+                let mut chunk = vec![0u8; size + 2];
+                reader.read_exact(&mut chunk).unwrap();
+                if size == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..size]);
+            }
+        } else {
+            body.resize(length.unwrap_or_default(), 0);
+            reader.read_exact(&mut body).unwrap();
+        }
+        (authorization, body)
+    }
+
+    /// Authorization header for alice:secret.
+    const ALICE: &str = "Basic YWxpY2U6c2VjcmV0";
+
+    /// Fake IPP printer answering `requests` requests, one per connection.
+    /// `job_id` is returned for Create-Job and Print-Job, jobs are completed immediately.
+    /// The `protected` operations are refused with HTTP 401 unless sent with [`ALICE`].
+    fn fake_printer(
+        operations: &'static [Operation],
+        job_id: Option<i32>,
+        protected: &'static [Operation],
+        requests: usize,
+    ) -> (u16, thread::JoinHandle<Vec<Received>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let printer = thread::spawn(move || {
+            let mut received = Vec::new();
+            for _ in 0..requests {
+                let (stream, _) = listener.accept().unwrap();
+                let mut writer = stream.try_clone().unwrap();
+                let (authorization, body) = read_request(&mut BufReader::new(stream));
+                let (request, offset) = IppMessage::decode(&body).unwrap();
+                received.push(Received {
+                    operation: request.code,
+                    authorization: authorization.clone(),
+                    document: body[offset..].to_vec(),
+                });
+                if protected.iter().any(|&op| op as u16 == request.code)
+                    && authorization.as_deref() != Some(ALICE)
+                {
+                    writer
+                        .write_all(
+                            b"HTTP/1.1 401 Unauthorized\r\n\
+                              WWW-Authenticate: Basic realm=\"printer\"\r\n\
+                              Content-Length: 0\r\n\r\n",
+                        )
+                        .unwrap();
+                    continue;
+                }
+
+                let mut response = IppMessage {
+                    version: (2, 0),
+                    code: status::SUCCESSFUL_OK,
+                    request_id: request.request_id,
+                    groups: Vec::new(),
+                };
+                response.add(
+                    group::OPERATION,
+                    Attribute::new("attributes-charset", Value::Charset(String::from("utf-8"))),
+                );
+                match request.code {
+                    code if code == Operation::GetPrinterAttributes as u16 => {
+                        let values = operations.iter().map(|&op| Value::Enum(op as i32));
+                        response.add(
+                            group::PRINTER,
+                            Attribute::with_values("operations-supported", values.collect()),
+                        );
+                        response.add(
+                            group::PRINTER,
+                            Attribute::new(
+                                "document-format-supported",
+                                Value::MimeMediaType(String::from("application/pdf")),
+                            ),
+                        );
+                    }
+                    code if code == Operation::CreateJob as u16
+                        || code == Operation::PrintJob as u16 =>
+                    {
+                        if let Some(job_id) = job_id {
+                            response
+                                .add(group::JOB, Attribute::new("job-id", Value::Integer(job_id)));
+                        }
+                    }
+                    code if code == Operation::GetJobAttributes as u16 => {
+                        response.add(
+                            group::JOB,
+                            Attribute::new("job-state", Value::Enum(JOB_STATE_COMPLETED)),
+                        );
+                        response.add(
+                            group::JOB,
+                            Attribute::new("job-impressions-completed", Value::Integer(2)),
+                        );
+                    }
+                    _ => {}
+                }
+
+                let response = response.encode();
+                write!(
+                    writer,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/ipp\r\nContent-Length: {}\r\n\r\n",
+                    response.len()
+                )
+                .unwrap();
+                writer.write_all(&response).unwrap();
+            }
+            received
+        });
+        (port, printer)
+    }
+
+    fn job(uri: &str, vars: &[(&str, &str)]) -> (BackendData, NamedTempFile) {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"%PDF-1.4\n%test\n").unwrap();
+        let mut env = vars
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect::<HashMap<_, _>>();
+        env.insert(String::from("DEVICE_URI"), uri.to_owned());
+        let args = [
+            "ipp",
+            "5",
+            "alice",
+            "report",
+            "1",
+            "",
+            file.path().to_str().unwrap(),
+        ]
+        .map(String::from);
+        (BackendData::from_args(&args, &env).unwrap(), file)
+    }
+
+    #[test]
+    fn create_job_and_monitor() {
+        let operations = &[
+            Operation::GetPrinterAttributes,
+            Operation::CreateJob,
+            Operation::SendDocument,
+            Operation::GetJobAttributes,
+        ];
+        let (port, printer) = fake_printer(operations, Some(12), &[], 4);
+        let uri = format!("ipp://127.0.0.1:{}/ipp/print", port);
+        let (data, _file) = job(
+            &uri,
+            &[("AUTH_USERNAME", "alice"), ("AUTH_PASSWORD", "secret")],
+        );
+
+        let code = IppBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = printer.join().unwrap();
+        let sent = received.iter().map(|r| r.operation).collect::<Vec<_>>();
+        assert_eq!(
+            sent,
+            [
+                Operation::GetPrinterAttributes as u16,
+                Operation::CreateJob as u16,
+                Operation::SendDocument as u16,
+                Operation::GetJobAttributes as u16,
+            ]
+        );
+        assert_eq!(received[2].document, b"%PDF-1.4\n%test\n");
+        // credentials are only sent when the printer asks for them
+        assert!(received.iter().all(|r| r.authorization.is_none()));
+    }
+
+    #[test]
+    fn authenticates_when_asked() {
+        let operations = &[Operation::GetPrinterAttributes, Operation::PrintJob];
+        let (port, printer) = fake_printer(operations, Some(3), &[Operation::PrintJob], 3);
+        let uri = format!(
+            "ipp://127.0.0.1:{}/ipp/print?waitjob=false&plainauth=true",
+            port
+        );
+        let (data, _file) = job(
+            &uri,
+            &[("AUTH_USERNAME", "alice"), ("AUTH_PASSWORD", "secret")],
+        );
+
+        let code = IppBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = printer.join().unwrap();
+        let sent = received
+            .iter()
+            .map(|r| (r.operation, r.authorization.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sent,
+            [
+                (Operation::GetPrinterAttributes as u16, None),
+                (Operation::PrintJob as u16, None),
+                (Operation::PrintJob as u16, Some(ALICE)),
+            ]
+        );
+        // the document is sent again with the credentials
+        assert_eq!(received[2].document, b"%PDF-1.4\n%test\n");
+    }
+
+    #[test]
+    fn no_credentials_in_cleartext() {
+        let operations = &[Operation::GetPrinterAttributes, Operation::PrintJob];
+        let (port, printer) = fake_printer(operations, Some(3), &[Operation::PrintJob], 2);
+        let uri = format!("ipp://127.0.0.1:{}/ipp/print?waitjob=false", port);
+        let (data, _file) = job(
+            &uri,
+            &[("AUTH_USERNAME", "alice"), ("AUTH_PASSWORD", "secret")],
+        );
+
+        let code = IppBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::StopQueue);
+
+        let received = printer.join().unwrap();
+        assert_eq!(received.len(), 2);
+        assert!(received.iter().all(|r| r.authorization.is_none()));
+    }
+
+    #[test]
+    fn rejected_credentials() {
+        let operations = &[Operation::GetPrinterAttributes, Operation::PrintJob];
+        let (port, printer) = fake_printer(operations, Some(3), &[Operation::PrintJob], 3);
+        let uri = format!(
+            "ipp://127.0.0.1:{}/ipp/print?waitjob=false&plainauth=true",
+            port
+        );
+        let (data, _file) = job(
+            &uri,
+            &[("AUTH_USERNAME", "alice"), ("AUTH_PASSWORD", "wrong")],
+        );
+
+        let result = IppBackend::new().submit_job(&data);
+        assert!(matches!(result, Err(BackendError::AuthRequired(_))));
+        assert_eq!(printer.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn print_job_without_job_id() {
+        let operations = &[Operation::GetPrinterAttributes, Operation::PrintJob];
+        let (port, printer) = fake_printer(operations, None, &[], 2);
+        let uri = format!("ipp://127.0.0.1:{}/ipp/print?waitjob=false", port);
+        let (data, _file) = job(&uri, &[]);
+
+        let code = IppBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::ErrorPolicy);
+
+        let received = printer.join().unwrap();
+        assert_eq!(received[1].operation, Operation::PrintJob as u16);
+        assert_eq!(received[1].document, b"%PDF-1.4\n%test\n");
+    }
+
+    #[test]
+    fn gives_up_when_refused() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let uri = format!("ipp://127.0.0.1:{}/ipp/print?contimeout=0", port);
+        let (data, _file) = job(&uri, &[]);
+
+        let code = IppBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::ErrorPolicy);
+    }
+
+    #[test]
+    fn certificate_verification() {
+        let credentials = [("AUTH_USERNAME", "alice"), ("AUTH_PASSWORD", "secret")];
+
+        let (data, _file) = job("ipps://printer/ipp/print", &credentials);
+        let mut backend = IppBackend::new();
+        backend
+            .setup(&data, &IppOptions::from_uri(&data.printer_uri).unwrap())
+            .unwrap();
+        assert!(!backend.client.accept_invalid_certs);
+        assert!(backend.authorization.is_some());
+
+        let (data, _file) = job("ipps://printer/ipp/print?verify=false", &credentials);
+        let mut backend = IppBackend::new();
+        backend
+            .setup(&data, &IppOptions::from_uri(&data.printer_uri).unwrap())
+            .unwrap();
+        assert!(backend.client.accept_invalid_certs);
+        assert!(backend.authorization.is_none());
+
+        let (data, _file) = job("ipp://printer/ipp/print", &credentials);
+        let mut backend = IppBackend::new();
+        backend
+            .setup(&data, &IppOptions::from_uri(&data.printer_uri).unwrap())
+            .unwrap();
+        assert!(backend.authorization.is_none());
+
+        let (data, _file) = job("ipp://printer/ipp/print?plainauth=true", &credentials);
+        let mut backend = IppBackend::new();
+        backend
+            .setup(&data, &IppOptions::from_uri(&data.printer_uri).unwrap())
+            .unwrap();
+        assert!(backend.authorization.is_some());
+    }
+}
@@ -0,0 +1,608 @@
+//! LPD (RFC 1179) backend: `lpd://host[:port]/queue[?options]`.
+//!
+//! Supported URI options:
+//!
+//! * `banner=on|off` - ask the server to print a banner page, default off;
+//! * `format=c|d|f|g|l|n|o|p|r|t|v` - print file format, default `l` (raw);
+//! * `mode=standard|stream` - stream mode sends the data without a length,
+//!   for servers that cannot spool large jobs;
+//! * `order=control,data|data,control` - which file to send first, default control file first;
+//! * `reserve=none|rfc1179|any` - bind to a reserved source port (721-731 or any below 1024),
+//!   which requires root privileges;
+//! * `contimeout=seconds` - how long to retry connecting before giving up, default one week.
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use url::Url;
+
+use super::{
+    connect_host, connect_with_retry, local_hostname, parse_bool, scheme_device, DEFAULT_CONTIMEOUT,
+};
+use crate::{
+    device::{DeviceClass, DeviceInfo},
+    is_cancelled,
+    status::StatusWriter,
+    Backend, BackendData, BackendError, ExitCode, Result,
+};
+
+const DEFAULT_PORT: u16 = 515;
+/// Socket timeout, the cancellation flag is checked in between.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the server may stall reading the job or acknowledging it.
+const IO_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reserve {
+    None,
+    /// Source ports 721-731 as required by RFC 1179.
+    Rfc1179,
+    /// Any privileged source port.
+    Any,
+}
+
+impl Reserve {
+    fn ports(&self) -> Option<RangeInclusive<u16>> {
+        match *self {
+            Reserve::None => None,
+            Reserve::Rfc1179 => Some(721..=731),
+            Reserve::Any => Some(512..=1023),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    ControlData,
+    DataControl,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LpdOptions {
+    pub banner: bool,
+    pub format: char,
+    pub stream: bool,
+    pub order: Order,
+    pub reserve: Reserve,
+    pub contimeout: Duration,
+}
+
+impl Default for LpdOptions {
+    fn default() -> LpdOptions {
+        LpdOptions {
+            banner: false,
+            format: 'l',
+            stream: false,
+            order: Order::ControlData,
+            reserve: Reserve::None,
+            contimeout: DEFAULT_CONTIMEOUT,
+        }
+    }
+}
+
+impl LpdOptions {
+    pub fn from_uri(uri: &Url) -> Result<LpdOptions> {
+        let mut options = LpdOptions::default();
+        for (name, value) in uri.query_pairs() {
+            let invalid = || BackendError::InvalidOption {
+                name: name.to_string(),
+                value: value.to_string(),
+            };
+            match name.as_ref() {
+                "banner" => options.banner = parse_bool(&value).ok_or_else(invalid)?,
+                "format" => {
+                    options.format = match value.as_ref() {
+                        "c" | "d" | "f" | "g" | "l" | "n" | "o" | "p" | "r" | "t" | "v" => {
+                            value.chars().next().unwrap_or('l')
+                        }
+                        _ => return Err(invalid()),
+                    }
+                }
+                "mode" => {
+                    options.stream = match value.as_ref() {
+                        "standard" => false,
+                        "stream" => true,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "order" => {
+                    options.order = match value.as_ref() {
+                        "control,data" => Order::ControlData,
+                        "data,control" => Order::DataControl,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "reserve" => {
+                    options.reserve = match value.as_ref() {
+                        "none" => Reserve::None,
+                        "rfc1179" => Reserve::Rfc1179,
+                        "any" => Reserve::Any,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "contimeout" => {
+                    let secs = value.parse::<u64>().map_err(|_| invalid())?;
+                    options.contimeout = Duration::from_secs(secs);
+                }
+                _ => debug!("Ignoring unknown URI option {}", name),
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Debug)]
+enum LpdError {
+    IOError(io::Error),
+    /// Non-zero acknowledgement byte.
+    Nack(u8),
+    /// The job was cancelled during the transfer.
+    Cancelled,
+}
+
+impl From<io::Error> for LpdError {
+    fn from(error: io::Error) -> LpdError {
+        LpdError::IOError(error)
+    }
+}
+
+/// Map a negative acknowledgement to an exit code, using the LPRng codes:
+/// 1 - queue stopped, 2 - temporary failure, 3 - job rejected.
+pub fn nack_exit_code(code: u8) -> ExitCode {
+    match code {
+        0 => ExitCode::Success,
+        1 => ExitCode::StopQueue,
+        3 => ExitCode::CancelJob,
+        _ => ExitCode::ErrorPolicy,
+    }
+}
+
+/// Control file lines may not contain control characters and are limited in length.
+fn sanitize(value: &str, max_len: usize) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_control() { '?' } else { c })
+        .take(max_len)
+        .collect()
+}
+
+/// Build the control file for the job. `host` is the local host name.
+pub fn control_file(data: &BackendData, host: &str, options: &LpdOptions) -> String {
+    let host = sanitize(host, 31);
+    let user = sanitize(&data.user_name, 31);
+    let title = sanitize(&data.title, 99);
+    let data_file = data_file_name(data, &host);
+
+    let mut control = format!("H{}\nP{}\nJ{}\n", host, user, title);
+    if options.banner {
+        control.push_str(&format!("C{}\nL{}\n", host, user));
+    }
+    for _ in 0..data.copies {
+        control.push_str(&format!("{}{}\n", options.format, data_file));
+    }
+    control.push_str(&format!("U{}\nN{}\n", data_file, title));
+    control
+}
+
+fn data_file_name(data: &BackendData, host: &str) -> String {
+    format!("dfA{:03}{}", data.job_id.0 % 1000, sanitize(host, 15))
+}
+
+fn control_file_name(data: &BackendData, host: &str) -> String {
+    format!("cfA{:03}{}", data.job_id.0 % 1000, sanitize(host, 15))
+}
+
+fn connect_reserved(
+    addr: SocketAddr,
+    ports: RangeInclusive<u16>,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::AddrInUse, "no free reserved port");
+    for port in ports.rev() {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], port).into(),
+            SocketAddr::V6(_) => ([0u16; 8], port).into(),
+        };
+        match socket.bind(&SockAddr::from(local)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                last_error = e;
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        match socket.connect_timeout(&SockAddr::from(addr), timeout) {
+            Ok(()) => return Ok(socket.into()),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => last_error = e,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_error)
+}
+
+fn connect(host: &str, port: u16, reserve: Reserve, timeout: Duration) -> io::Result<TcpStream> {
+    let ports = match reserve.ports() {
+        Some(ports) => ports,
+        None => return connect_host(host, port, timeout),
+    };
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address for host");
+    for addr in (host, port).to_socket_addrs()? {
+        match connect_reserved(addr, ports.clone(), timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Sends jobs to a remote LPD queue.
+#[derive(Default)]
+pub struct LpdBackend;
+
+impl LpdBackend {
+    pub fn new() -> LpdBackend {
+        LpdBackend
+    }
+
+    /// Wait for an I/O operation, giving up if the job is cancelled or the server
+    /// makes no progress for [`IO_TIMEOUT`]. The stream timeout must be [`POLL_INTERVAL`].
+    fn wait<F>(mut op: F) -> std::result::Result<usize, LpdError>
+    where
+        F: FnMut() -> io::Result<usize>,
+    {
+        let start = Instant::now();
+        loop {
+            if is_cancelled() {
+                return Err(LpdError::Cancelled);
+            }
+            match op() {
+                Ok(n) => return Ok(n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if start.elapsed() >= IO_TIMEOUT {
+                        return Err(e.into());
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn write_all(stream: &mut TcpStream, mut buf: &[u8]) -> std::result::Result<(), LpdError> {
+        while !buf.is_empty() {
+            match LpdBackend::wait(|| stream.write(buf))? {
+                0 => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    fn ack(stream: &mut TcpStream) -> std::result::Result<(), LpdError> {
+        let mut status = [0u8; 1];
+        if LpdBackend::wait(|| stream.read(&mut status))? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        match status[0] {
+            0 => Ok(()),
+            code => Err(LpdError::Nack(code)),
+        }
+    }
+
+    fn command(stream: &mut TcpStream, command: &str) -> std::result::Result<(), LpdError> {
+        debug!("Sending LPD command {:?}", command.trim_end());
+        LpdBackend::write_all(stream, command.as_bytes())?;
+        LpdBackend::ack(stream)
+    }
+
+    fn send_control(
+        stream: &mut TcpStream,
+        name: &str,
+        control: &str,
+    ) -> std::result::Result<(), LpdError> {
+        LpdBackend::command(stream, &format!("\x02{} {}\n", control.len(), name))?;
+        LpdBackend::write_all(stream, control.as_bytes())?;
+        LpdBackend::write_all(stream, b"\0")?;
+        LpdBackend::ack(stream)
+    }
+
+    fn send_data<W: Write>(
+        stream: &mut TcpStream,
+        name: &str,
+        data: &BackendData,
+        options: &LpdOptions,
+        status: &mut StatusWriter<W>,
+    ) -> std::result::Result<(), LpdError> {
+        if !options.stream {
+            // the length has to be sent before the data
+            data.job_source.path()?;
+        }
+        let size = data.job_source.size()?;
+        let mut reader = data.job_source.reader()?;
+
+        let length = if options.stream {
+            0
+        } else {
+            size.unwrap_or_default()
+        };
+        LpdBackend::command(stream, &format!("\x03{} {}\n", length, name))?;
+
+        let mut buf = vec![0u8; 65536];
+        let mut sent = 0u64;
+        let mut percent = 0;
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            LpdBackend::write_all(stream, &buf[..n])?;
+            sent += n as u64;
+            if let Some(size) = size.filter(|&size| size > 0) {
+                if sent * 100 / size != percent {
+                    percent = sent * 100 / size;
+                    status.progress(percent as u32)?;
+                }
+            }
+        }
+
+        if options.stream {
+            // the end of the data is signalled by closing the connection
+            stream.shutdown(Shutdown::Write)?;
+            Ok(())
+        } else {
+            LpdBackend::write_all(stream, b"\0")?;
+            LpdBackend::ack(stream)
+        }
+    }
+
+    fn print<W: Write>(
+        stream: &mut TcpStream,
+        queue: &str,
+        data: &BackendData,
+        options: &LpdOptions,
+        status: &mut StatusWriter<W>,
+    ) -> std::result::Result<(), LpdError> {
+        let host = local_hostname();
+        let control = control_file(data, &host, options);
+        let control_name = control_file_name(data, &host);
+        let data_name = data_file_name(data, &host);
+
+        LpdBackend::command(stream, &format!("\x02{}\n", queue))?;
+
+        // in stream mode the data file must come last
+        if options.order == Order::DataControl && !options.stream {
+            LpdBackend::send_data(stream, &data_name, data, options, status)?;
+            LpdBackend::send_control(stream, &control_name, &control)
+        } else {
+            LpdBackend::send_control(stream, &control_name, &control)?;
+            LpdBackend::send_data(stream, &data_name, data, options, status)
+        }
+    }
+}
+
+impl Backend for LpdBackend {
+    fn name(&self) -> &str {
+        "lpd"
+    }
+
+    fn description(&self) -> &str {
+        "LPD/LPR Host or Printer"
+    }
+
+    fn discover(&self) -> Vec<DeviceInfo> {
+        vec![scheme_device(
+            DeviceClass::Network,
+            self.name(),
+            self.description(),
+        )]
+    }
+
+    fn submit_job(&mut self, data: &BackendData) -> Result<ExitCode> {
+        let uri = &data.printer_uri;
+        let host = uri.host_str().ok_or(BackendError::NoUri)?;
+        let port = uri.port().unwrap_or(DEFAULT_PORT);
+        let queue = uri.path().trim_start_matches('/');
+        if queue.is_empty() {
+            error!("No queue name in device URI");
+            return Ok(ExitCode::StopQueue);
+        }
+        let options = LpdOptions::from_uri(uri)?;
+        let mut status = StatusWriter::stderr().with_job_id(data.job_id);
+
+        info!("Connecting to {}:{}", host, port);
+        let connect = |timeout| connect(host, port, options.reserve, timeout);
+        let mut stream = match connect_with_retry(options.contimeout, &mut status, connect)? {
+            Some(stream) => stream,
+            None => return Ok(ExitCode::ErrorPolicy),
+        };
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        stream.set_write_timeout(Some(POLL_INTERVAL))?;
+        info!("Sending job to queue {}", queue);
+
+        match LpdBackend::print(&mut stream, queue, data, &options, &mut status) {
+            Ok(()) => {
+                info!("Job accepted by {}", host);
+                Ok(ExitCode::Success)
+            }
+            Err(LpdError::Nack(code)) => {
+                error!("Remote host rejected the job with status {}", code);
+                Ok(nack_exit_code(code))
+            }
+            Err(LpdError::IOError(e)) => {
+                error!("Unable to send job: {}", e);
+                Ok(ExitCode::ErrorPolicy)
+            }
+            Err(LpdError::Cancelled) => {
+                // closing the connection without completing the data file
+                // makes the server discard the job
+                let _ = stream.shutdown(Shutdown::Both);
+                Ok(ExitCode::CancelJob)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        thread,
+    };
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    /// What the fake daemon received: the queue command and the control and data files.
+    #[derive(Debug, Default)]
+    struct Received {
+        queue: String,
+        control: String,
+        data: Vec<u8>,
+        /// Order of the subcommands, `\x02` for the control file and `\x03` for the data file.
+        order: Vec<char>,
+    }
+
+    fn read_line(reader: &mut impl BufRead) -> Option<String> {
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line).unwrap() {
+            0 => None,
+            _ => Some(String::from_utf8(line).unwrap().trim_end().to_owned()),
+        }
+    }
+
+    /// Fake LPD daemon accepting one job. `nack` is sent instead of the
+    /// acknowledgement of the receive job command if it is not zero.
+    fn fake_daemon(nack: u8) -> (u16, thread::JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let daemon = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received = Received::default();
+
+            let command = read_line(&mut reader).unwrap();
+            received.queue = command.trim_start_matches('\x02').to_owned();
+            writer.write_all(&[nack]).unwrap();
+            if nack != 0 {
+                return received;
+            }
+
+            while let Some(command) = read_line(&mut reader) {
+                let kind = command.chars().next().unwrap();
+                let length = command[1..]
+                    .split(' ')
+                    .next()
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap();
+                received.order.push(kind);
+                writer.write_all(b"\0").unwrap();
+
+                let mut file = Vec::new();
+                if length == 0 {
+                    // stream mode, the data ends with the connection
+                    reader.read_to_end(&mut file).unwrap();
+                } else {
+                    file.resize(length + 1, 0);
+                    reader.read_exact(&mut file).unwrap();
+                    assert_eq!(file.pop(), Some(0));
+                    writer.write_all(b"\0").unwrap();
+                }
+                match kind {
+                    '\x02' => received.control = String::from_utf8(file).unwrap(),
+                    _ => received.data = file,
+                }
+            }
+            received
+        });
+        (port, daemon)
+    }
+
+    fn job(uri: &str) -> (BackendData, NamedTempFile) {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"%!PS\nshowpage\n").unwrap();
+        let env = HashMap::from([(String::from("DEVICE_URI"), uri.to_owned())]);
+        let args = [
+            "lpd",
+            "1234",
+            "alice",
+            "report",
+            "2",
+            "",
+            file.path().to_str().unwrap(),
+        ]
+        .map(String::from);
+        (BackendData::from_args(&args, &env).unwrap(), file)
+    }
+
+    #[test]
+    fn standard_mode() {
+        let (port, daemon) = fake_daemon(0);
+        let (data, _file) = job(&format!("lpd://127.0.0.1:{}/raw?banner=on", port));
+
+        let code = LpdBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = daemon.join().unwrap();
+        assert_eq!(received.queue, "raw");
+        assert_eq!(received.order, ['\x02', '\x03']);
+        assert_eq!(received.data, b"%!PS\nshowpage\n");
+        let lines = received.control.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"Palice"));
+        assert!(lines.contains(&"Jreport"));
+        assert!(lines.contains(&"Lalice"));
+        assert_eq!(lines.iter().filter(|l| l.starts_with("ldfA234")).count(), 2);
+    }
+
+    #[test]
+    fn data_file_first() {
+        let (port, daemon) = fake_daemon(0);
+        let (data, _file) = job(&format!("lpd://127.0.0.1:{}/raw?order=data,control", port));
+
+        let code = LpdBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = daemon.join().unwrap();
+        assert_eq!(received.order, ['\x03', '\x02']);
+        assert_eq!(received.data, b"%!PS\nshowpage\n");
+    }
+
+    #[test]
+    fn stream_mode() {
+        let (port, daemon) = fake_daemon(0);
+        let (data, _file) = job(&format!("lpd://127.0.0.1:{}/raw?mode=stream", port));
+
+        let code = LpdBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = daemon.join().unwrap();
+        assert_eq!(received.order, ['\x02', '\x03']);
+        assert_eq!(received.data, b"%!PS\nshowpage\n");
+    }
+
+    #[test]
+    fn queue_stopped() {
+        let (port, daemon) = fake_daemon(1);
+        let (data, _file) = job(&format!("lpd://127.0.0.1:{}/raw", port));
+
+        let code = LpdBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::StopQueue);
+        assert_eq!(daemon.join().unwrap().queue, "raw");
+    }
+}
@@ -0,0 +1,922 @@
+//! Email backend: `mailto:user@example.com[,user2@example.com][?options]`.
+//!
+//! Sends the job as an attachment of a MIME message with the job title as the
+//! subject. The job data is attached once regardless of the number of copies.
+//!
+//! Supported URI options:
+//!
+//! * `server=host[:port]` - SMTP server, default `localhost:25`;
+//! * `from=address` - sender address template, default `{user}@{host}` where `{user}`
+//!   is the job owner and `{host}` the local host name;
+//! * `cc=address` - additional recipient, may be repeated;
+//! * `starttls=auto|required|off` - use STARTTLS if the server offers it, insist on it,
+//!   or never use it, default `auto`;
+//! * `tls=true|false` - connect with TLS right away (port 465), default false;
+//! * `verify=true|false` - verify the server certificate, default true;
+//! * `plainauth=true|false` - allow sending credentials over an unencrypted
+//!   connection, default false;
+//! * `contimeout=seconds` - how long to retry connecting before giving up, default one week.
+//!
+//! If credentials are supplied through `AUTH_USERNAME` and `AUTH_PASSWORD` the
+//! backend authenticates with `AUTH PLAIN` or `AUTH LOGIN`. Unless `plainauth` is
+//! set, this is only done once the connection is encrypted.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{debug, error, info};
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use super::{
+    connect_host, connect_with_retry, local_hostname, parse_bool, scheme_device, DEFAULT_CONTIMEOUT,
+};
+use crate::{
+    auth::{AuthInfo, Credentials},
+    device::{DeviceClass, DeviceInfo},
+    http::{tls_connect, Stream},
+    is_cancelled,
+    status::StatusWriter,
+    Backend, BackendData, BackendError, ExitCode, Result,
+};
+
+const DEFAULT_PORT: u16 = 25;
+const DEFAULT_TLS_PORT: u16 = 465;
+const DEFAULT_FROM: &str = "{user}@{host}";
+const TIMEOUT: Duration = Duration::from_secs(300);
+/// Input bytes per line of base64 output, giving 76 characters per line.
+const BASE64_LINE: usize = 57;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartTls {
+    Auto,
+    Required,
+    Off,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailtoOptions {
+    pub server: String,
+    pub port: Option<u16>,
+    pub from: String,
+    pub cc: Vec<String>,
+    pub starttls: StartTls,
+    pub tls: bool,
+    pub verify: bool,
+    pub plain_auth: bool,
+    pub contimeout: Duration,
+}
+
+impl Default for MailtoOptions {
+    fn default() -> MailtoOptions {
+        MailtoOptions {
+            server: String::from("localhost"),
+            port: None,
+            from: DEFAULT_FROM.to_owned(),
+            cc: Vec::new(),
+            starttls: StartTls::Auto,
+            tls: false,
+            verify: true,
+            plain_auth: false,
+            contimeout: DEFAULT_CONTIMEOUT,
+        }
+    }
+}
+
+impl MailtoOptions {
+    pub fn from_uri(uri: &Url) -> Result<MailtoOptions> {
+        let mut options = MailtoOptions::default();
+        for (name, value) in uri.query_pairs() {
+            let invalid = || BackendError::InvalidOption {
+                name: name.to_string(),
+                value: value.to_string(),
+            };
+            match name.as_ref() {
+                "server" => match value.rsplit_once(':') {
+                    Some((host, port)) if !host.is_empty() => {
+                        options.server = host.to_owned();
+                        options.port = Some(port.parse().map_err(|_| invalid())?);
+                    }
+                    Some(_) => return Err(invalid()),
+                    None => options.server = value.to_string(),
+                },
+                "from" => {
+                    if !is_address(&value.replace("{user}", "u").replace("{host}", "h")) {
+                        return Err(invalid());
+                    }
+                    options.from = value.to_string();
+                }
+                "cc" => {
+                    if !is_address(&value) {
+                        return Err(invalid());
+                    }
+                    options.cc.push(value.to_string());
+                }
+                "starttls" => {
+                    options.starttls = match value.as_ref() {
+                        "auto" => StartTls::Auto,
+                        "required" => StartTls::Required,
+                        "off" => StartTls::Off,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "tls" => options.tls = parse_bool(&value).ok_or_else(invalid)?,
+                "verify" => options.verify = parse_bool(&value).ok_or_else(invalid)?,
+                "plainauth" => options.plain_auth = parse_bool(&value).ok_or_else(invalid)?,
+                "contimeout" => {
+                    let secs = value.parse::<u64>().map_err(|_| invalid())?;
+                    options.contimeout = Duration::from_secs(secs);
+                }
+                _ => debug!("Ignoring unknown URI option {}", name),
+            }
+        }
+        Ok(options)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(if self.tls {
+            DEFAULT_TLS_PORT
+        } else {
+            DEFAULT_PORT
+        })
+    }
+}
+
+/// Minimal sanity check of an email address, mainly to keep CR/LF out of SMTP commands.
+fn is_address(address: &str) -> bool {
+    match address.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.is_empty()
+                && !address
+                    .chars()
+                    .any(|c| c.is_control() || c.is_whitespace() || "<>,;".contains(c))
+        }
+        None => false,
+    }
+}
+
+/// Sender address for the job, `None` if the expanded template is not a valid address.
+pub fn sender(template: &str, data: &BackendData) -> Option<String> {
+    let from = template
+        .replace("{user}", &data.user_name)
+        .replace("{host}", &local_hostname());
+    Some(from).filter(|from| is_address(from))
+}
+
+/// Recipients from the URI path.
+pub fn recipients(uri: &Url) -> Vec<String> {
+    percent_decode_str(uri.path())
+        .decode_utf8_lossy()
+        .split(',')
+        .map(|address| address.trim().to_owned())
+        .filter(|address| !address.is_empty())
+        .collect()
+}
+
+/// Map an SMTP reply code onto the backend exit code.
+pub fn reply_exit_code(code: u16) -> ExitCode {
+    match code {
+        200..=399 => ExitCode::Success,
+        530 | 534 | 535 | 538 => ExitCode::AuthRequired,
+        400..=499 => ExitCode::ErrorPolicy,
+        _ => ExitCode::CancelJob,
+    }
+}
+
+fn extension(content_type: &str) -> &'static str {
+    match content_type {
+        "application/pdf" => "pdf",
+        "application/postscript" => "ps",
+        "application/vnd.hp-PCL" => "pcl",
+        "image/pwg-raster" => "pwg",
+        "image/urf" => "urf",
+        "text/plain" => "txt",
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        _ => "bin",
+    }
+}
+
+/// Encode a header value as RFC 2047 encoded words if it is not plain ASCII.
+fn encode_header(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return value.to_owned();
+    }
+    // encoded words are limited to 75 characters
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut end = 0;
+    for (i, c) in value.char_indices() {
+        if i + c.len_utf8() - start > 45 {
+            words.push(&value[start..end]);
+            start = end;
+        }
+        end = i + c.len_utf8();
+    }
+    words.push(&value[start..end]);
+    words
+        .iter()
+        .map(|word| format!("=?UTF-8?B?{}?=", STANDARD.encode(word)))
+        .collect::<Vec<_>>()
+        .join("\r\n ")
+}
+
+/// Date in RFC 5322 format.
+fn rfc5322_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default() as libc::time_t;
+    // SAFETY: gmtime_r only writes to the provided tm structure
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::gmtime_r(&secs, &mut tm) }.is_null() {
+        return String::from("Thu, 01 Jan 1970 00:00:00 +0000");
+    }
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} +0000",
+        DAYS[tm.tm_wday.rem_euclid(7) as usize],
+        tm.tm_mday,
+        MONTHS[tm.tm_mon.rem_euclid(12) as usize],
+        tm.tm_year + 1900,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+/// Attachment file name made of safe characters only.
+fn file_name(data: &BackendData, content_type: &str) -> String {
+    let name = data
+        .title
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(64)
+        .collect::<String>();
+    let name = name.trim_matches(|c| c == '_' || c == '.');
+    let name = if name.is_empty() {
+        format!("job-{}", data.job_id)
+    } else {
+        name.to_owned()
+    };
+    let ext = extension(content_type);
+    if name.ends_with(&format!(".{}", ext)) {
+        name
+    } else {
+        format!("{}.{}", name, ext)
+    }
+}
+
+/// Headers and the text part of the message, up to the attachment data.
+pub fn message_head(
+    data: &BackendData,
+    from: &str,
+    to: &[String],
+    cc: &[String],
+    boundary: &str,
+    content_type: &str,
+    time: SystemTime,
+) -> String {
+    let title = if data.title.is_empty() {
+        format!("Print job {}", data.job_id)
+    } else {
+        data.title.clone()
+    };
+    let cc = if cc.is_empty() {
+        String::new()
+    } else {
+        format!("Cc: {}\r\n", cc.join(", "))
+    };
+    format!(
+        "From: {from}\r\n\
+         To: {to}\r\n\
+         {cc}\
+         Subject: {subject}\r\n\
+         Date: {date}\r\n\
+         Message-ID: <job-{job}.{secs}@{host}>\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/mixed; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Content-Transfer-Encoding: base64\r\n\
+         \r\n\
+         {text}\r\n\
+         --{boundary}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Transfer-Encoding: base64\r\n\
+         Content-Disposition: attachment; filename=\"{file_name}\"\r\n\
+         \r\n",
+        from = from,
+        to = to.join(", "),
+        cc = cc,
+        subject = encode_header(&title),
+        date = rfc5322_date(time),
+        job = data.job_id,
+        secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        host = local_hostname(),
+        boundary = boundary,
+        text = STANDARD.encode(format!(
+            "Print job {} \"{}\" from {}.\r\n",
+            data.job_id, data.title, data.user_name
+        )),
+        content_type = content_type,
+        file_name = file_name(data, content_type),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Reply {
+    code: u16,
+    lines: Vec<String>,
+}
+
+impl Reply {
+    fn is_positive(&self) -> bool {
+        (200..400).contains(&self.code)
+    }
+
+    fn text(&self) -> String {
+        self.lines.join(" ")
+    }
+}
+
+#[derive(Debug)]
+enum SmtpError {
+    IOError(io::Error),
+    /// Negative reply from the server.
+    Reply(Reply),
+    /// The server does not offer STARTTLS although it is required.
+    NoStartTls,
+    /// Credentials would have to be sent over an unencrypted connection.
+    Unencrypted,
+    /// The job was cancelled during the session.
+    Cancelled,
+}
+
+impl From<io::Error> for SmtpError {
+    fn from(e: io::Error) -> SmtpError {
+        SmtpError::IOError(e)
+    }
+}
+
+struct Smtp {
+    reader: BufReader<Stream>,
+    extensions: Vec<String>,
+}
+
+impl Smtp {
+    fn read_reply(&mut self) -> io::Result<Reply> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed by server",
+                ));
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse::<u16>().ok())
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("bad reply: {}", line))
+                })?;
+            lines.push(line.get(4..).unwrap_or_default().to_owned());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                debug!("SMTP reply {} {}", code, lines.join(" "));
+                return Ok(Reply { code, lines });
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let stream = self.reader.get_mut();
+        stream.write_all(data)?;
+        stream.flush()
+    }
+
+    /// Send a command and fail on a negative reply.
+    fn command(&mut self, command: &str) -> std::result::Result<Reply, SmtpError> {
+        self.send_line(command, command)
+    }
+
+    /// Send a line which is logged as `log`, used to keep credentials out of the log.
+    fn send_line(&mut self, line: &str, log: &str) -> std::result::Result<Reply, SmtpError> {
+        if is_cancelled() {
+            return Err(SmtpError::Cancelled);
+        }
+        debug!("SMTP command {}", log);
+        self.write(format!("{}\r\n", line).as_bytes())?;
+        let reply = self.read_reply()?;
+        if reply.is_positive() {
+            Ok(reply)
+        } else {
+            Err(SmtpError::Reply(reply))
+        }
+    }
+
+    fn ehlo(&mut self) -> std::result::Result<(), SmtpError> {
+        let reply = self.command(&format!("EHLO {}", local_hostname()))?;
+        self.extensions = reply
+            .lines
+            .iter()
+            .skip(1)
+            .map(|l| l.to_ascii_uppercase())
+            .collect();
+        Ok(())
+    }
+
+    fn supports(&self, extension: &str) -> Option<&str> {
+        self.extensions
+            .iter()
+            .find(|e| e.split(' ').next() == Some(extension))
+            .map(|e| e.as_str())
+    }
+
+    /// Upgrade the connection to TLS and repeat EHLO.
+    fn starttls(mut self, host: &str, verify: bool) -> std::result::Result<Smtp, SmtpError> {
+        self.command("STARTTLS")?;
+        let stream = match self.reader.into_inner() {
+            Stream::Plain(stream) => tls_connect(host, stream, !verify)?,
+            stream => stream,
+        };
+        let mut smtp = Smtp {
+            reader: BufReader::new(stream),
+            extensions: Vec::new(),
+        };
+        smtp.ehlo()?;
+        Ok(smtp)
+    }
+
+    fn authenticate(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> std::result::Result<(), SmtpError> {
+        let mechanisms = self.supports("AUTH").unwrap_or_default().to_owned();
+        if mechanisms.split(' ').any(|m| m == "PLAIN") {
+            let token = STANDARD.encode(format!("\0{}\0{}", username, password));
+            self.send_line(&format!("AUTH PLAIN {}", token), "AUTH PLAIN ...")?;
+        } else {
+            self.command("AUTH LOGIN")?;
+            self.send_line(&STANDARD.encode(username), "(username)")?;
+            self.send_line(&STANDARD.encode(password), "(password)")?;
+        }
+        Ok(())
+    }
+
+    /// Send the message body, the job data is base64 encoded on the fly.
+    /// On cancellation the message is left unterminated, so the server discards it.
+    fn data(
+        &mut self,
+        head: &str,
+        reader: &mut dyn Read,
+        boundary: &str,
+    ) -> std::result::Result<(), SmtpError> {
+        self.command("DATA")?;
+
+        let mut out = io::BufWriter::new(self.reader.get_mut());
+        out.write_all(head.as_bytes())?;
+        let mut buf = vec![0u8; BASE64_LINE * 1024];
+        loop {
+            if is_cancelled() {
+                return Err(SmtpError::Cancelled);
+            }
+            let mut filled = 0;
+            while filled < buf.len() {
+                let n = reader.read(&mut buf[filled..])?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            for line in buf[..filled].chunks(BASE64_LINE) {
+                out.write_all(STANDARD.encode(line).as_bytes())?;
+                out.write_all(b"\r\n")?;
+            }
+            if filled < buf.len() {
+                break;
+            }
+        }
+        // base64 lines never start with a dot, so no dot-stuffing is needed
+        write!(out, "--{}--\r\n.\r\n", boundary)?;
+        out.flush()?;
+        drop(out);
+
+        let reply = self.read_reply()?;
+        if reply.is_positive() {
+            Ok(())
+        } else {
+            Err(SmtpError::Reply(reply))
+        }
+    }
+}
+
+/// Sends each job as an email attachment.
+#[derive(Default)]
+pub struct MailtoBackend;
+
+impl MailtoBackend {
+    pub fn new() -> MailtoBackend {
+        MailtoBackend
+    }
+
+    fn send(
+        stream: Stream,
+        data: &BackendData,
+        options: &MailtoOptions,
+        from: &str,
+        to: &[String],
+    ) -> std::result::Result<(), SmtpError> {
+        let mut smtp = Smtp {
+            reader: BufReader::new(stream),
+            extensions: Vec::new(),
+        };
+        let greeting = smtp.read_reply()?;
+        if !greeting.is_positive() {
+            return Err(SmtpError::Reply(greeting));
+        }
+        smtp.ehlo()?;
+
+        let mut encrypted = options.tls;
+        if !options.tls && options.starttls != StartTls::Off {
+            if smtp.supports("STARTTLS").is_some() {
+                smtp = smtp.starttls(&options.server, options.verify)?;
+                encrypted = true;
+            } else if options.starttls == StartTls::Required {
+                return Err(SmtpError::NoStartTls);
+            }
+        }
+
+        if let Some(Credentials {
+            username: Some(ref username),
+            password: Some(ref password),
+            ..
+        }) = data.credentials
+        {
+            if !encrypted && !options.plain_auth {
+                return Err(SmtpError::Unencrypted);
+            }
+            smtp.authenticate(username, password)?;
+        }
+
+        smtp.command(&format!("MAIL FROM:<{}>", from))?;
+        for recipient in to.iter().chain(options.cc.iter()) {
+            smtp.command(&format!("RCPT TO:<{}>", recipient))?;
+        }
+
+        let content_type = data.content_type()?;
+
+        let now = SystemTime::now();
+        let boundary = format!(
+            "=_job{}_{}",
+            data.job_id,
+            now.duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or_default()
+        );
+        let head = message_head(data, from, to, &options.cc, &boundary, content_type, now);
+        smtp.data(&head, &mut data.job_source.reader()?, &boundary)?;
+
+        let _ = smtp.command("QUIT");
+        Ok(())
+    }
+}
+
+impl Backend for MailtoBackend {
+    fn name(&self) -> &str {
+        "mailto"
+    }
+
+    fn description(&self) -> &str {
+        "Email"
+    }
+
+    fn discover(&self) -> Vec<DeviceInfo> {
+        vec![scheme_device(
+            DeviceClass::Network,
+            self.name(),
+            self.description(),
+        )]
+    }
+
+    fn submit_job(&mut self, data: &BackendData) -> Result<ExitCode> {
+        let to = recipients(&data.printer_uri);
+        if to.is_empty() || !to.iter().all(|address| is_address(address)) {
+            return Err(BackendError::NoUri);
+        }
+        let options = MailtoOptions::from_uri(&data.printer_uri)?;
+        let from = match sender(&options.from, data) {
+            Some(from) => from,
+            None => {
+                error!("Invalid sender address for user {:?}", data.user_name);
+                return Ok(ExitCode::CancelJob);
+            }
+        };
+        let mut status = StatusWriter::stderr().with_job_id(data.job_id);
+
+        let (host, port) = (options.server.as_str(), options.port());
+        info!("Connecting to {}:{}", host, port);
+        let connect = |timeout| connect_host(host, port, timeout);
+        let stream = match connect_with_retry(options.contimeout, &mut status, connect)? {
+            Some(stream) => stream,
+            None => return Ok(ExitCode::ErrorPolicy),
+        };
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let stream = if options.tls {
+            match tls_connect(host, stream, !options.verify) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("TLS connection failed: {}", e);
+                    return Ok(ExitCode::ErrorPolicy);
+                }
+            }
+        } else {
+            Stream::Plain(stream)
+        };
+
+        match MailtoBackend::send(stream, data, &options, &from, &to) {
+            Ok(()) => {
+                info!("Mail sent to {}", to.join(", "));
+                Ok(ExitCode::Success)
+            }
+            Err(SmtpError::Reply(reply)) => {
+                error!("Mail server replied {} {}", reply.code, reply.text());
+                match reply_exit_code(reply.code) {
+                    ExitCode::AuthRequired => Err(BackendError::AuthRequired(vec![
+                        AuthInfo::Username,
+                        AuthInfo::Password,
+                    ])),
+                    code => Ok(code),
+                }
+            }
+            Err(SmtpError::NoStartTls) => {
+                error!("Mail server does not support STARTTLS");
+                Ok(ExitCode::StopQueue)
+            }
+            Err(SmtpError::Unencrypted) => {
+                error!("Not sending credentials over an unencrypted connection, see plainauth");
+                Ok(ExitCode::StopQueue)
+            }
+            Err(SmtpError::Cancelled) => Ok(ExitCode::CancelJob),
+            Err(SmtpError::IOError(e)) => {
+                error!("Unable to send mail: {}", e);
+                Ok(ExitCode::ErrorPolicy)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::{CupsBackend, JobContext};
+
+    /// Fake SMTP server accepting one session, returns the lines it received.
+    fn fake_server(extensions: &'static [&'static str]) -> (u16, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received = Vec::new();
+            writer.write_all(b"220 fake ESMTP\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_owned();
+                received.push(line.clone());
+                let reply = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    String::from("250 queued\r\n")
+                } else if line.starts_with("EHLO") {
+                    let mut reply = String::from("250-fake\r\n");
+                    for (i, extension) in extensions.iter().enumerate() {
+                        let sep = if i + 1 == extensions.len() { ' ' } else { '-' };
+                        reply.push_str(&format!("250{}{}\r\n", sep, extension));
+                    }
+                    reply
+                } else if line == "DATA" {
+                    in_data = true;
+                    String::from("354 go ahead\r\n")
+                } else if line.starts_with("AUTH PLAIN") {
+                    String::from("235 ok\r\n")
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    String::from("250 ok\r\n")
+                };
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+            received
+        });
+        (port, server)
+    }
+
+    fn job(uri: &str, user: &str, vars: &[(&str, &str)]) -> (BackendData, NamedTempFile) {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"%PDF-1.4\n%test\n").unwrap();
+        let mut env = vars
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect::<HashMap<_, _>>();
+        env.insert(String::from("DEVICE_URI"), uri.to_owned());
+        let args = [
+            "mailto",
+            "42",
+            user,
+            "report",
+            "1",
+            "",
+            file.path().to_str().unwrap(),
+        ]
+        .map(String::from);
+        (BackendData::from_args(&args, &env).unwrap(), file)
+    }
+
+    #[test]
+    fn sends_message() {
+        let (port, server) = fake_server(&["8BITMIME"]);
+        let uri = format!("mailto:alice@example.com?server=127.0.0.1:{}", port);
+        let (data, _file) = job(&uri, "bob", &[]);
+
+        let code = MailtoBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = server.join().unwrap();
+        assert!(received[1].starts_with("MAIL FROM:<bob@"));
+        assert_eq!(received[2], "RCPT TO:<alice@example.com>");
+        assert!(received.contains(&String::from("Subject: report")));
+        assert!(received.contains(&String::from("Content-Type: application/pdf")));
+        assert!(!received.iter().any(|line| line.starts_with("Cc:")));
+        assert_eq!(received.last().unwrap(), "QUIT");
+    }
+
+    #[test]
+    fn copies_recipients() {
+        let (port, server) = fake_server(&["8BITMIME"]);
+        let uri = format!(
+            "mailto:alice@example.com?server=127.0.0.1:{}&cc=carol@example.com&cc=dave@example.com",
+            port
+        );
+        let (data, _file) = job(&uri, "bob", &[]);
+
+        let code = MailtoBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[3], "RCPT TO:<carol@example.com>");
+        assert_eq!(received[4], "RCPT TO:<dave@example.com>");
+        assert!(received.contains(&String::from("To: alice@example.com")));
+        assert!(received.contains(&String::from("Cc: carol@example.com, dave@example.com")));
+    }
+
+    #[test]
+    fn cancelled_between_commands() {
+        let context = JobContext::default();
+        let cancel = context.cancel.clone();
+        // the job is cancelled while the server handles MAIL FROM
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            writer.write_all(b"220 fake ESMTP\r\n").unwrap();
+            let mut received = Vec::new();
+            for line in BufReader::new(stream).lines() {
+                let line = line.unwrap();
+                if line.starts_with("MAIL FROM") {
+                    cancel.cancel();
+                }
+                received.push(line);
+                writer.write_all(b"250 ok\r\n").unwrap();
+            }
+            received
+        });
+
+        let (_, file) = job("mailto:alice@example.com", "bob", &[]);
+        let args = ["mailto", "3", "bob", "report", "1", ""]
+            .map(String::from)
+            .into_iter()
+            .chain([file.path().to_string_lossy().into_owned()])
+            .collect::<Vec<_>>();
+        let uri = format!("mailto:alice@example.com?server=127.0.0.1:{}", port);
+        let env = HashMap::from([(String::from("DEVICE_URI"), uri)]);
+
+        let code = CupsBackend::new(MailtoBackend::new()).execute_with(&args, &env, context);
+        assert_eq!(code, ExitCode::CancelJob);
+        let received = server.join().unwrap();
+        assert!(received.last().unwrap().starts_with("MAIL FROM"));
+    }
+
+    #[test]
+    fn refuses_unencrypted_auth() {
+        let (port, server) = fake_server(&["AUTH PLAIN LOGIN"]);
+        let uri = format!("mailto:alice@example.com?server=127.0.0.1:{}", port);
+        let credentials = [("AUTH_USERNAME", "bob"), ("AUTH_PASSWORD", "secret")];
+        let (data, _file) = job(&uri, "bob", &credentials);
+
+        let code = MailtoBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::StopQueue);
+        let received = server.join().unwrap();
+        assert!(!received.iter().any(|line| line.starts_with("AUTH")));
+    }
+
+    #[test]
+    fn plain_auth_when_allowed() {
+        let (port, server) = fake_server(&["AUTH PLAIN LOGIN"]);
+        let uri = format!(
+            "mailto:alice@example.com?server=127.0.0.1:{}&plainauth=true",
+            port
+        );
+        let credentials = [("AUTH_USERNAME", "bob"), ("AUTH_PASSWORD", "secret")];
+        let (data, _file) = job(&uri, "bob", &credentials);
+
+        let code = MailtoBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+        let received = server.join().unwrap();
+        let token = STANDARD.encode("\0bob\0secret");
+        assert!(received.contains(&format!("AUTH PLAIN {}", token)));
+    }
+
+    #[test]
+    fn declared_content_type() {
+        let (port, server) = fake_server(&["8BITMIME"]);
+        let uri = format!("mailto:alice@example.com?server=127.0.0.1:{}", port);
+        let vars = [
+            ("CONTENT_TYPE", "text/plain"),
+            ("FINAL_CONTENT_TYPE", "application/vnd.cups-postscript"),
+        ];
+        let (data, _file) = job(&uri, "bob", &vars);
+
+        let code = MailtoBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = server.join().unwrap();
+        assert!(received.contains(&String::from("Content-Type: application/postscript")));
+    }
+
+    #[test]
+    fn rejects_injected_sender() {
+        let (data, _file) = job("mailto:alice@example.com", "bob\r\nRCPT TO:<eve@x>", &[]);
+        assert_eq!(sender(DEFAULT_FROM, &data), None);
+        assert_eq!(
+            MailtoBackend::new().submit_job(&data).unwrap(),
+            ExitCode::CancelJob
+        );
+
+        let (data, _file) = job("mailto:alice@example.com", "bob", &[]);
+        assert_eq!(
+            sender("print-{user}@example.com", &data).as_deref(),
+            Some("print-bob@example.com")
+        );
+    }
+
+    #[test]
+    fn helpers() {
+        let uri = Url::parse("mailto:a@example.com,%20b@example.com").unwrap();
+        assert_eq!(recipients(&uri), ["a@example.com", "b@example.com"]);
+        assert_eq!(reply_exit_code(250), ExitCode::Success);
+        assert_eq!(reply_exit_code(535), ExitCode::AuthRequired);
+        assert_eq!(reply_exit_code(451), ExitCode::ErrorPolicy);
+        assert_eq!(reply_exit_code(550), ExitCode::CancelJob);
+        assert_eq!(encode_header("plain"), "plain");
+        assert_eq!(encode_header("a\r\nb"), "=?UTF-8?B?YQ0KYg==?=");
+    }
+}
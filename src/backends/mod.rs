@@ -0,0 +1,161 @@
+//! Built-in backend implementations.
+
+use std::{
+    cmp,
+    ffi::CStr,
+    io::{self, Write},
+    net::{TcpStream, ToSocketAddrs},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::error;
+
+use crate::{
+    device::{DeviceClass, DeviceInfo},
+    is_cancelled,
+    status::StatusWriter,
+    Result,
+};
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Default `contimeout` of the network backends, one week like CUPS.
+pub(crate) const DEFAULT_CONTIMEOUT: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+pub mod file;
+pub mod ipp;
+pub mod lpd;
+pub mod mailto;
+pub mod pipe;
+pub mod smb;
+pub mod socket;
+pub mod upload;
+
+/// Parse a boolean URI option value.
+pub(crate) fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Sleep for the given duration unless the job gets cancelled.
+/// Returns false if the job was cancelled.
+pub(crate) fn sleep_unless_cancelled(duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while !is_cancelled() {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep((deadline - now).min(Duration::from_millis(100)));
+    }
+    false
+}
+
+/// Device advertised by `discover()` for a URI scheme.
+pub(crate) fn scheme_device(device_class: DeviceClass, scheme: &str, info: &str) -> DeviceInfo {
+    let mut device = DeviceInfo::new(device_class, scheme);
+    device.info = info.to_owned();
+    device
+}
+
+/// Quote a string as a JSON string literal.
+pub(crate) fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Host name of this machine, "localhost" if it cannot be determined.
+pub(crate) fn local_hostname() -> String {
+    let mut buf = [0u8; 256];
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len() - 1) };
+    if rc != 0 {
+        return String::from("localhost");
+    }
+    CStr::from_bytes_until_nul(&buf)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| String::from("localhost"))
+}
+
+/// Connect to the first reachable address of the host.
+pub(crate) fn connect_host(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address for host");
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Connect to the printer, retrying with increasing delays until `contimeout` expires.
+/// The closure receives the timeout for a single attempt. Returns `None` if no
+/// connection could be made or the job was cancelled. The `connecting-to-device`
+/// state reason is set while retrying and removed on return.
+pub(crate) fn connect_with_retry<W, T, F>(
+    contimeout: Duration,
+    status: &mut StatusWriter<W>,
+    mut connect: F,
+) -> Result<Option<T>>
+where
+    W: Write,
+    F: FnMut(Duration) -> io::Result<T>,
+{
+    status.add_state_reasons(&["connecting-to-device"])?;
+    let result = retry(contimeout, status, &mut connect);
+    status.remove_state_reasons(&["connecting-to-device"])?;
+    result
+}
+
+fn retry<W, T, F>(
+    contimeout: Duration,
+    status: &mut StatusWriter<W>,
+    connect: &mut F,
+) -> Result<Option<T>>
+where
+    W: Write,
+    F: FnMut(Duration) -> io::Result<T>,
+{
+    let start = Instant::now();
+    let mut delay = Duration::from_secs(5);
+
+    loop {
+        let remaining = contimeout.saturating_sub(start.elapsed());
+        let timeout = cmp::max(cmp::min(remaining, MAX_RETRY_DELAY), Duration::from_secs(1));
+        match connect(timeout) {
+            Ok(connection) => return Ok(Some(connection)),
+            Err(e) => {
+                if start.elapsed() + delay >= contimeout {
+                    error!("Unable to connect to printer: {}", e);
+                    return Ok(None);
+                }
+                status.info(&format!(
+                    "Unable to connect to printer ({}), retrying in {} seconds",
+                    e,
+                    delay.as_secs()
+                ))?;
+                if !sleep_unless_cancelled(delay) {
+                    return Ok(None);
+                }
+                delay = cmp::min(delay * 2, MAX_RETRY_DELAY);
+            }
+        }
+    }
+}
@@ -0,0 +1,386 @@
+//! Pipe backend: `pipe:/path/to/command[?options]`.
+//!
+//! Runs the command with the job data on stdin. Lines the command writes to
+//! stderr are forwarded to the scheduler, so it can use the usual `INFO:`,
+//! `STATE:` or `PAGE:` messages itself. The job is described by these
+//! environment variables:
+//!
+//! * `JOB_ID`, `JOB_USER`, `JOB_TITLE`, `JOB_COPIES`;
+//! * `JOB_OPTIONS` - the job options in the same format as the backend argument;
+//! * `JOB_FILE` - path of the job data;
+//! * `DEVICE_URI` - the device URI without credentials.
+//!
+//! Supported URI options:
+//!
+//! * `arg=value` - command argument, may be repeated;
+//! * `timeout=seconds` - stop the command if it runs longer, default no limit;
+//! * `exit=status:code,...` - map exit statuses of the command to backend exit codes,
+//!   where the code is one of `success`, `error-policy`, `auth-required`, `hold-job`,
+//!   `stop-queue` or `cancel-job` and the status `*` matches any other status.
+//!   By default 0 is `success` and everything else `error-policy`.
+//!
+//! The command runs in its own process group. On timeout or cancellation the whole
+//! group is sent SIGTERM, and SIGKILL if the command does not exit in time.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    os::unix::process::{CommandExt, ExitStatusExt},
+    process::{Child, Command, ExitStatus, Stdio},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use super::scheme_device;
+use crate::{
+    device::{DeviceClass, DeviceInfo},
+    is_cancelled,
+    options::encode_options,
+    spawn_job_thread,
+    status::StatusWriter,
+    Backend, BackendData, BackendError, ExitCode, Result,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a command may take to exit after SIGTERM before it is killed.
+const KILL_GRACE: Duration = Duration::from_secs(5);
+/// How long to wait for the rest of the stderr output once the command has exited.
+/// Processes it left running may keep stderr open indefinitely.
+const RELAY_GRACE: Duration = Duration::from_secs(1);
+
+pub fn parse_exit_code(keyword: &str) -> Option<ExitCode> {
+    match keyword {
+        "success" => Some(ExitCode::Success),
+        "error-policy" => Some(ExitCode::ErrorPolicy),
+        "auth-required" => Some(ExitCode::AuthRequired),
+        "hold-job" => Some(ExitCode::HoldJob),
+        "stop-queue" => Some(ExitCode::StopQueue),
+        "cancel-job" => Some(ExitCode::CancelJob),
+        _ => None,
+    }
+}
+
+/// Maps the exit status of the command onto the backend exit code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExitTable {
+    codes: HashMap<i32, ExitCode>,
+    default: ExitCode,
+}
+
+impl Default for ExitTable {
+    fn default() -> ExitTable {
+        ExitTable {
+            codes: HashMap::new(),
+            default: ExitCode::ErrorPolicy,
+        }
+    }
+}
+
+impl ExitTable {
+    /// Parse a `status:code,...` list.
+    pub fn parse(value: &str) -> Option<ExitTable> {
+        let mut table = ExitTable::default();
+        for entry in value.split(',').filter(|e| !e.is_empty()) {
+            let (status, code) = entry.split_once(':')?;
+            let code = parse_exit_code(code.trim())?;
+            match status.trim() {
+                "*" => table.default = code,
+                status => {
+                    table.codes.insert(status.parse().ok()?, code);
+                }
+            }
+        }
+        Some(table)
+    }
+
+    pub fn exit_code(&self, status: i32) -> ExitCode {
+        match self.codes.get(&status) {
+            Some(code) => code.clone(),
+            None if status == 0 => ExitCode::Success,
+            None => self.default.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PipeOptions {
+    pub args: Vec<String>,
+    pub timeout: Option<Duration>,
+    pub exit_table: ExitTable,
+}
+
+impl PipeOptions {
+    pub fn from_uri(uri: &Url) -> Result<PipeOptions> {
+        let mut options = PipeOptions::default();
+        for (name, value) in uri.query_pairs() {
+            let invalid = || BackendError::InvalidOption {
+                name: name.to_string(),
+                value: value.to_string(),
+            };
+            match name.as_ref() {
+                "arg" => options.args.push(value.to_string()),
+                "timeout" => {
+                    let secs = value.parse::<u64>().map_err(|_| invalid())?;
+                    options.timeout = Some(Duration::from_secs(secs)).filter(|t| !t.is_zero());
+                }
+                "exit" => options.exit_table = ExitTable::parse(&value).ok_or_else(invalid)?,
+                _ => debug!("Ignoring unknown URI option {}", name),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Send a signal to the process group of the command.
+fn signal_group(child: &Child, signal: libc::c_int) {
+    // SAFETY: kill only sends a signal, the command leads its own process group
+    unsafe { libc::kill(-(child.id() as libc::pid_t), signal) };
+}
+
+/// Ask the command and the processes it started to terminate, killing them
+/// if the command does not exit in time.
+fn terminate(child: &mut Child) -> std::io::Result<ExitStatus> {
+    signal_group(child, libc::SIGTERM);
+    let deadline = Instant::now() + KILL_GRACE;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            // processes left behind could keep stderr open
+            signal_group(child, libc::SIGKILL);
+            return Ok(status);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    signal_group(child, libc::SIGKILL);
+    child.wait()
+}
+
+/// Wait for the stderr relay to see the end of the output, for at most [`RELAY_GRACE`].
+fn finish_relay(relay: JoinHandle<()>) {
+    let deadline = Instant::now() + RELAY_GRACE;
+    while !relay.is_finished() {
+        if Instant::now() >= deadline {
+            debug!("Command output is still open, not waiting for it");
+            return;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    let _ = relay.join();
+}
+
+/// Feeds each job to a local command.
+#[derive(Default)]
+pub struct PipeBackend;
+
+impl PipeBackend {
+    pub fn new() -> PipeBackend {
+        PipeBackend
+    }
+
+    fn command(data: &BackendData, program: &str, options: &PipeOptions) -> Result<Command> {
+        let job_options = encode_options(
+            data.options
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+
+        let mut command = Command::new(program);
+        command
+            .args(&options.args)
+            .env("JOB_ID", data.job_id.to_string())
+            .env("JOB_USER", &data.user_name)
+            .env("JOB_TITLE", &data.title)
+            .env("JOB_COPIES", data.copies.to_string())
+            .env("JOB_OPTIONS", job_options)
+            .env("JOB_FILE", data.job_source.path()?)
+            .env(
+                "DEVICE_URI",
+                data.printer_uri.without_credentials().as_str(),
+            )
+            .stdin(File::open(data.job_source.path()?)?)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .process_group(0);
+        Ok(command)
+    }
+
+    /// Wait for the command, enforcing the timeout and cancellation.
+    /// Returns `None` if the command had to be stopped.
+    fn wait(child: &mut Child, timeout: Option<Duration>) -> Result<Option<ExitStatus>> {
+        let start = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Some(status));
+            }
+            if is_cancelled() {
+                info!("Job cancelled, stopping command");
+                terminate(child)?;
+                return Ok(None);
+            }
+            if timeout.map(|t| start.elapsed() >= t).unwrap_or(false) {
+                error!("Command timed out, stopping it");
+                terminate(child)?;
+                return Ok(None);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl Backend for PipeBackend {
+    fn name(&self) -> &str {
+        "pipe"
+    }
+
+    fn description(&self) -> &str {
+        "Pipe to Command"
+    }
+
+    fn discover(&self) -> Vec<DeviceInfo> {
+        vec![scheme_device(
+            DeviceClass::File,
+            self.name(),
+            self.description(),
+        )]
+    }
+
+    fn submit_job(&mut self, data: &BackendData) -> Result<ExitCode> {
+        let program = percent_decode_str(data.printer_uri.path())
+            .decode_utf8_lossy()
+            .into_owned();
+        if program.is_empty() {
+            return Err(BackendError::NoUri);
+        }
+        let options = PipeOptions::from_uri(&data.printer_uri)?;
+
+        info!("Running {}", program);
+        let mut child = match PipeBackend::command(data, &program, &options)?.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Unable to run {}: {}", program, e);
+                return Ok(ExitCode::StopQueue);
+            }
+        };
+
+        let stderr = child.stderr.take();
+        let job_id = data.job_id;
+        let relay = spawn_job_thread(move || {
+            let mut status = StatusWriter::stderr().with_job_id(job_id);
+            if let Some(stderr) = stderr {
+                for line in BufReader::new(stderr).lines() {
+                    match line {
+                        Ok(line) => {
+                            let _ = status.relay(&line);
+                        }
+                        Err(_) => break,
+                    }
+                }
+            }
+        });
+
+        let result = PipeBackend::wait(&mut child, options.timeout);
+        finish_relay(relay);
+
+        let status = match result? {
+            Some(status) => status,
+            None if is_cancelled() => return Ok(ExitCode::CancelJob),
+            None => return Ok(ExitCode::ErrorPolicy),
+        };
+        match status.code() {
+            Some(code) => {
+                let exit_code = options.exit_table.exit_code(code);
+                debug!("Command exited with status {} ({:?})", code, exit_code);
+                Ok(exit_code)
+            }
+            None => {
+                warn!(
+                    "Command terminated by signal {}",
+                    status.signal().unwrap_or_default()
+                );
+                Ok(ExitCode::ErrorPolicy)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn job(uri: &str) -> (BackendData, NamedTempFile) {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"%!PS\nshowpage\n").unwrap();
+        let env = HashMap::from([(String::from("DEVICE_URI"), uri.to_owned())]);
+        let args = [
+            "pipe",
+            "4",
+            "alice",
+            "report",
+            "1",
+            "",
+            file.path().to_str().unwrap(),
+        ]
+        .map(String::from);
+        (BackendData::from_args(&args, &env).unwrap(), file)
+    }
+
+    /// Run a shell script as the command.
+    fn run(script: &str, options: &str) -> ExitCode {
+        let mut uri = Url::parse("pipe:/bin/sh").unwrap();
+        uri.query_pairs_mut()
+            .append_pair("arg", "-c")
+            .append_pair("arg", script);
+        let uri = format!("{}{}", uri, options);
+        let (data, _file) = job(&uri);
+        PipeBackend::new().submit_job(&data).unwrap()
+    }
+
+    #[test]
+    fn exit_table() {
+        let table = ExitTable::parse("2:hold-job, 3:stop-queue,*:cancel-job").unwrap();
+        assert_eq!(table.exit_code(0), ExitCode::Success);
+        assert_eq!(table.exit_code(2), ExitCode::HoldJob);
+        assert_eq!(table.exit_code(3), ExitCode::StopQueue);
+        assert_eq!(table.exit_code(1), ExitCode::CancelJob);
+
+        let table = ExitTable::parse("0:error-policy").unwrap();
+        assert_eq!(table.exit_code(0), ExitCode::ErrorPolicy);
+        assert_eq!(ExitTable::default().exit_code(1), ExitCode::ErrorPolicy);
+
+        assert_eq!(ExitTable::parse(""), Some(ExitTable::default()));
+        assert!(ExitTable::parse("2").is_none());
+        assert!(ExitTable::parse("x:success").is_none());
+        assert!(ExitTable::parse("2:retry").is_none());
+    }
+
+    #[test]
+    fn exit_status_mapping() {
+        assert_eq!(run("cat >/dev/null", ""), ExitCode::Success);
+        assert_eq!(run("exit 1", ""), ExitCode::ErrorPolicy);
+        assert_eq!(run("exit 3", "&exit=3:hold-job"), ExitCode::HoldJob);
+        assert_eq!(run("kill -9 $$", "&exit=*:success"), ExitCode::ErrorPolicy);
+    }
+
+    #[test]
+    fn timeout_stops_process_group() {
+        // the shell waits for sleep, which keeps stderr open unless it is stopped too
+        let start = Instant::now();
+        assert_eq!(run("sleep 30; true", "&timeout=1"), ExitCode::ErrorPolicy);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn background_process_keeps_stderr() {
+        let start = Instant::now();
+        assert_eq!(run("sleep 5 & exit 0", ""), ExitCode::Success);
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+}
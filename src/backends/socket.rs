@@ -0,0 +1,479 @@
+//! AppSocket (HP JetDirect) backend: `socket://host[:port][?options]`.
+//!
+//! Supported URI options:
+//!
+//! * `waiteof=true|false` - wait up to five minutes for the printer to close the
+//!   connection after the job has been sent, default true;
+//! * `contimeout=seconds` - how long to retry connecting before giving up, default one week;
+//! * `snmp=true|false` - accepted for compatibility with the CUPS socket backend.
+//!   SNMP queries are not implemented, so the option is ignored;
+//! * `pjl=true|false` - wrap the job in PJL with the title, user, copies, sides and
+//!   resolution of the job, default false. The printer makes the copies and its PJL
+//!   status messages are reported as pages and printer state reasons.
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use url::Url;
+
+use super::{connect_host, connect_with_retry, parse_bool, scheme_device, DEFAULT_CONTIMEOUT};
+use crate::{
+    backchannel::BackChannel,
+    device::{DeviceClass, DeviceInfo},
+    is_cancelled,
+    pjl::{JobEvent, JobStatus, PjlJob, Ustatus, UstatusParser},
+    sidechannel::{Response, SideChannelHandler, BIDI_SUPPORTED},
+    spawn_job_thread,
+    status::StatusWriter,
+    Backend, BackendData, BackendError, ExitCode, JobId, JobSource, Result,
+};
+
+const DEFAULT_PORT: u16 = 9100;
+const BUFFER_SIZE: usize = 65536;
+/// Socket timeout between checks for cancellation.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long `waiteof` waits for the printer to close the connection.
+const WAIT_EOF_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub wait_eof: bool,
+    pub contimeout: Duration,
+    pub pjl: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        SocketOptions {
+            wait_eof: true,
+            contimeout: DEFAULT_CONTIMEOUT,
+            pjl: false,
+        }
+    }
+}
+
+impl SocketOptions {
+    pub fn from_uri(uri: &Url) -> Result<SocketOptions> {
+        let mut options = SocketOptions::default();
+        for (name, value) in uri.query_pairs() {
+            let invalid = || BackendError::InvalidOption {
+                name: name.to_string(),
+                value: value.to_string(),
+            };
+            match name.as_ref() {
+                "waiteof" => options.wait_eof = parse_bool(&value).ok_or_else(invalid)?,
+                "snmp" => {
+                    if parse_bool(&value).ok_or_else(invalid)? {
+                        warn!("SNMP is not supported, ignoring snmp=true");
+                    }
+                }
+                "pjl" => options.pjl = parse_bool(&value).ok_or_else(invalid)?,
+                "contimeout" => {
+                    let secs = value.parse::<u64>().map_err(|_| invalid())?;
+                    options.contimeout = Duration::from_secs(secs);
+                }
+                _ => debug!("Ignoring unknown URI option {}", name),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Side-channel replies for a bidirectional connection to the printer.
+struct SocketSideChannel {
+    connected: Arc<AtomicBool>,
+}
+
+impl SideChannelHandler for SocketSideChannel {
+    fn get_bidi(&mut self) -> Response {
+        Response::ok(vec![BIDI_SUPPORTED])
+    }
+
+    fn get_connected(&mut self) -> Response {
+        Response::ok(vec![self.connected.load(Ordering::SeqCst) as u8])
+    }
+}
+
+/// Streams the job to a raw TCP port, relaying printer responses to the back-channel.
+#[derive(Default)]
+pub struct SocketBackend {
+    connected: Arc<AtomicBool>,
+}
+
+impl SocketBackend {
+    pub fn new() -> SocketBackend {
+        SocketBackend::default()
+    }
+
+    /// Report a PJL status message to the scheduler. `reason` is the state
+    /// reason set by the previous device status, if any.
+    fn report<W: Write>(
+        message: &Ustatus,
+        status: &mut StatusWriter<W>,
+        reason: &mut Option<&'static str>,
+    ) -> io::Result<()> {
+        match message {
+            Ustatus::Page(page) => status.page(*page, 1),
+            Ustatus::Job(JobStatus {
+                event: JobEvent::End,
+                pages: Some(pages),
+                ..
+            }) => status.total_pages(*pages),
+            Ustatus::Device(device) => {
+                let current = device.state_reason();
+                if current != *reason {
+                    if let Some(previous) = reason.take() {
+                        status.remove_state_reasons(&[previous])?;
+                    }
+                    if let Some(current) = current {
+                        status.add_state_reasons(&[current])?;
+                    }
+                    *reason = current;
+                }
+                Ok(())
+            }
+            Ustatus::Job(_) => Ok(()),
+        }
+    }
+
+    /// Relay data sent by the printer to the back-channel until the connection is closed.
+    /// If the job is wrapped in PJL, status messages are also reported to the scheduler.
+    fn relay(mut stream: TcpStream, pjl: Option<JobId>) -> thread::JoinHandle<()> {
+        spawn_job_thread(move || {
+            let back = BackChannel::open();
+            let mut ustatus = pjl.map(|job_id| {
+                (
+                    UstatusParser::new(),
+                    StatusWriter::stderr().with_job_id(job_id),
+                )
+            });
+            let mut reason = None;
+            let _ = stream.set_read_timeout(Some(POLL_INTERVAL));
+            let mut buf = [0u8; 4096];
+            loop {
+                match stream.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        debug!("Received {} bytes of back-channel data", n);
+                        if let Some(ref back) = back {
+                            let _ = back.write_timeout(&buf[..n], Some(POLL_INTERVAL));
+                        }
+                        if let Some((ref mut parser, ref mut status)) = ustatus {
+                            for message in parser.push(&buf[..n]) {
+                                debug!("PJL status: {:?}", message);
+                                let _ = SocketBackend::report(&message, status, &mut reason);
+                            }
+                        }
+                    }
+                    Err(ref e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) && !is_cancelled() => {}
+                    Err(_) => break,
+                }
+            }
+        })
+    }
+
+    /// Write all data, waiting as long as the printer does not accept more. Returns
+    /// early if the job is cancelled. The stream write timeout must be [`POLL_INTERVAL`].
+    fn write_all(stream: &mut TcpStream, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() && !is_cancelled() {
+            match stream.write(buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => buf = &buf[n..],
+                Err(ref e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for the relay thread to see the printer close the connection, closing
+    /// it after [`WAIT_EOF_TIMEOUT`]. The relay stops by itself on cancellation.
+    fn wait_eof(stream: &TcpStream, relay: &thread::JoinHandle<()>) {
+        let deadline = Instant::now() + WAIT_EOF_TIMEOUT;
+        while !relay.is_finished() {
+            if Instant::now() >= deadline {
+                warn!("Printer did not close the connection, giving up waiting");
+                let _ = stream.shutdown(Shutdown::Both);
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    fn send<W: Write>(
+        stream: &mut TcpStream,
+        reader: &mut dyn Read,
+        total: Option<u64>,
+        status: &mut StatusWriter<W>,
+    ) -> io::Result<u64> {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        let mut sent = 0u64;
+        let mut percent = 0;
+
+        while !is_cancelled() {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            SocketBackend::write_all(stream, &buf[..n])?;
+            sent += n as u64;
+
+            if let Some(total) = total.filter(|&total| total > 0) {
+                if sent * 100 / total != percent {
+                    percent = sent * 100 / total;
+                    status.progress(percent as u32)?;
+                }
+            }
+        }
+        Ok(sent)
+    }
+}
+
+impl Backend for SocketBackend {
+    fn name(&self) -> &str {
+        "socket"
+    }
+
+    fn description(&self) -> &str {
+        "AppSocket/HP JetDirect"
+    }
+
+    fn side_channel_handler(&mut self) -> Option<Box<dyn SideChannelHandler + Send>> {
+        Some(Box::new(SocketSideChannel {
+            connected: self.connected.clone(),
+        }))
+    }
+
+    fn discover(&self) -> Vec<DeviceInfo> {
+        vec![scheme_device(
+            DeviceClass::Network,
+            self.name(),
+            self.description(),
+        )]
+    }
+
+    fn submit_job(&mut self, data: &BackendData) -> Result<ExitCode> {
+        let uri = &data.printer_uri;
+        let host = uri.host_str().ok_or(BackendError::NoUri)?;
+        let port = uri.port().unwrap_or(DEFAULT_PORT);
+        let options = SocketOptions::from_uri(uri)?;
+        let mut status = StatusWriter::stderr().with_job_id(data.job_id);
+
+        info!("Connecting to {}:{}", host, port);
+        let connect = |timeout| connect_host(host, port, timeout);
+        let mut stream = match connect_with_retry(options.contimeout, &mut status, connect)? {
+            Some(stream) => stream,
+            None => return Ok(ExitCode::ErrorPolicy),
+        };
+        info!("Connected to {}:{}", host, port);
+        stream.set_write_timeout(Some(POLL_INTERVAL))?;
+
+        let pjl = if options.pjl {
+            let mut job = PjlJob::from_data(data)?;
+            job.ustatus = true;
+            Some(job)
+        } else {
+            None
+        };
+        // with PJL the printer makes the copies
+        let copies = if pjl.is_some() { 1 } else { data.copies };
+
+        let relay = SocketBackend::relay(stream.try_clone()?, pjl.as_ref().map(|_| data.job_id));
+        self.connected.store(true, Ordering::SeqCst);
+
+        if copies > 1 {
+            // every copy reads the data again
+            data.job_source.path()?;
+        }
+        let size = data.job_source.size()?;
+
+        let mut result = Ok(0);
+        for _ in 0..copies {
+            if let (JobSource::JobFile(_), None) = (&data.job_source, &pjl) {
+                status.page(1, 1)?;
+            }
+            let mut reader: Box<dyn Read> = match pjl {
+                Some(ref job) => Box::new(job.wrap(data.job_source.reader()?)),
+                None => data.job_source.reader()?,
+            };
+            result = match (
+                result,
+                SocketBackend::send(&mut stream, &mut reader, size, &mut status),
+            ) {
+                (Ok(total), Ok(sent)) => Ok(total + sent),
+                (_, Err(e)) | (Err(e), _) => Err(e),
+            };
+            if result.is_err() || is_cancelled() {
+                break;
+            }
+        }
+
+        self.connected.store(false, Ordering::SeqCst);
+        if options.wait_eof && result.is_ok() && !is_cancelled() {
+            info!("Waiting for printer to finish");
+            let _ = stream.shutdown(Shutdown::Write);
+            SocketBackend::wait_eof(&stream, &relay);
+        } else {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let _ = relay.join();
+
+        match result {
+            Ok(bytes) => {
+                info!("Sent {} bytes to printer", bytes);
+                Ok(ExitCode::Success)
+            }
+            Err(e) => {
+                error!("Unable to send print data: {}", e);
+                Ok(ExitCode::ErrorPolicy)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::TcpListener};
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::{CupsBackend, JobContext};
+
+    /// Fake printer accepting one connection, returns the data it received.
+    fn fake_printer() -> (u16, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let printer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+        (port, printer)
+    }
+
+    fn job(uri: &str, copies: &str, content: &[u8]) -> (BackendData, NamedTempFile) {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content).unwrap();
+        let env = HashMap::from([(String::from("DEVICE_URI"), uri.to_owned())]);
+        let args = [
+            "socket",
+            "7",
+            "alice",
+            "report",
+            copies,
+            "",
+            file.path().to_str().unwrap(),
+        ]
+        .map(String::from);
+        (BackendData::from_args(&args, &env).unwrap(), file)
+    }
+
+    #[test]
+    fn sends_copies() {
+        let (port, printer) = fake_printer();
+        let uri = format!("socket://127.0.0.1:{}", port);
+        let (data, _file) = job(&uri, "2", b"%!PS\nshowpage\n");
+
+        let code = SocketBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+        assert_eq!(printer.join().unwrap(), b"%!PS\nshowpage\n%!PS\nshowpage\n");
+    }
+
+    #[test]
+    fn wraps_job_in_pjl() {
+        let (port, printer) = fake_printer();
+        let uri = format!("socket://127.0.0.1:{}?pjl=true", port);
+        let (data, _file) = job(&uri, "3", b"%!PS\nshowpage\n");
+
+        let code = SocketBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = String::from_utf8(printer.join().unwrap()).unwrap();
+        assert!(received.contains("@PJL SET COPIES=3\r\n"));
+        assert!(received.contains("@PJL ENTER LANGUAGE=POSTSCRIPT\r\n"));
+        assert_eq!(received.matches("showpage").count(), 1);
+        assert!(received.ends_with("\x1b%-12345X"));
+    }
+
+    #[test]
+    fn cancels_blocked_write() {
+        // the printer accepts the connection but never reads
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let printer = thread::spawn(move || listener.accept().unwrap());
+
+        let uri = format!("socket://127.0.0.1:{}", port);
+        let (_, file) = job(&uri, "1", &vec![b'x'; 64 << 20]);
+        let env = HashMap::from([(String::from("DEVICE_URI"), uri)]);
+        let args = ["socket", "7", "alice", "report", "1", ""]
+            .map(String::from)
+            .into_iter()
+            .chain([file.path().to_string_lossy().into_owned()])
+            .collect::<Vec<_>>();
+
+        let context = JobContext::default();
+        let cancel = context.cancel.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(500));
+            cancel.cancel();
+        });
+        let start = Instant::now();
+        let code = CupsBackend::new(SocketBackend::new()).execute_with(&args, &env, context);
+        assert_eq!(code, ExitCode::CancelJob);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        canceller.join().unwrap();
+        drop(printer.join().unwrap());
+    }
+
+    #[test]
+    fn gives_up_when_refused() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let uri = format!("socket://127.0.0.1:{}?contimeout=0", port);
+        let (data, _file) = job(&uri, "1", b"data");
+
+        let code = SocketBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::ErrorPolicy);
+    }
+
+    #[test]
+    fn uri_options() {
+        let uri = Url::parse("socket://printer?waiteof=false&contimeout=30&snmp=false").unwrap();
+        let options = SocketOptions::from_uri(&uri).unwrap();
+        assert!(!options.wait_eof);
+        assert_eq!(options.contimeout, Duration::from_secs(30));
+
+        let uri = Url::parse("socket://printer?snmp=true").unwrap();
+        assert_eq!(
+            SocketOptions::from_uri(&uri).unwrap(),
+            SocketOptions::default()
+        );
+        let uri = Url::parse("socket://printer?snmp=maybe").unwrap();
+        assert!(SocketOptions::from_uri(&uri).is_err());
+        let uri = Url::parse("socket://printer?waiteof=maybe").unwrap();
+        assert!(SocketOptions::from_uri(&uri).is_err());
+    }
+}
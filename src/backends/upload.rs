@@ -0,0 +1,683 @@
+//! HTTP upload backend: `http://host[:port]/path[?options]` and `https://...`.
+//!
+//! Uploads each job to a print gateway. The job is described by these request headers:
+//!
+//! * `X-Job-Id`, `X-Job-Copies`;
+//! * `X-Job-User`, `X-Job-Title` - percent-encoded UTF-8;
+//! * `X-Job-Options` - the job options as a JSON object.
+//!
+//! Supported URI options:
+//!
+//! * `body=raw|multipart` - send the job data as the request body, or as the `file`
+//!   part of a `multipart/form-data` body with the metadata in a `metadata` JSON part,
+//!   default `raw`;
+//! * `method=POST|PUT` - request method, default `POST`;
+//! * `auth=auto|basic|bearer` - with `auto` the backend uses basic authentication if
+//!   `AUTH_USERNAME` and `AUTH_PASSWORD` are set and a bearer token taken from
+//!   `AUTH_PASSWORD` if only the password is set;
+//! * `redirects=n` - maximum number of redirects to follow, default 5;
+//! * `retries=n` - how often to retry after a server error or a network failure, default 3;
+//! * `verify=true|false` - verify the server certificate, default true;
+//! * `plainauth=true|false` - allow sending credentials over an unencrypted `http`
+//!   connection, default false.
+//!
+//! The job data is uploaded once regardless of the number of copies. Credentials are
+//! only sent to a verified `https` server unless `plainauth` is set, and never to
+//! another server the upload is redirected to.
+//!
+//! Only redirects which repeat the request with its body (307 and 308) are followed.
+//! A 301, 302 or 303 redirect lets the client change the request to a GET (RFC 7231),
+//! which would not deliver the job, so the upload fails instead.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Cursor, Read},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{debug, error, info, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use url::Url;
+
+use super::{json_string, parse_bool, scheme_device, sleep_unless_cancelled};
+use crate::{
+    auth::AuthInfo,
+    device::{DeviceClass, DeviceInfo},
+    http::{Body, Client, Response},
+    uri::redact,
+    Backend, BackendData, BackendError, ExitCode, Result,
+};
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyMode {
+    Raw,
+    Multipart,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMode {
+    Auto,
+    Basic,
+    Bearer,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadOptions {
+    pub body: BodyMode,
+    pub method: String,
+    pub auth: AuthMode,
+    pub redirects: u32,
+    pub retries: u32,
+    pub verify: bool,
+    pub plain_auth: bool,
+}
+
+impl Default for UploadOptions {
+    fn default() -> UploadOptions {
+        UploadOptions {
+            body: BodyMode::Raw,
+            method: String::from("POST"),
+            auth: AuthMode::Auto,
+            redirects: 5,
+            retries: 3,
+            verify: true,
+            plain_auth: false,
+        }
+    }
+}
+
+impl UploadOptions {
+    /// Parse the backend options. They are removed from the URI before the upload.
+    pub fn from_uri(uri: &Url) -> Result<UploadOptions> {
+        let mut options = UploadOptions::default();
+        for (name, value) in uri.query_pairs() {
+            let invalid = || BackendError::InvalidOption {
+                name: name.to_string(),
+                value: value.to_string(),
+            };
+            match name.as_ref() {
+                "body" => {
+                    options.body = match value.as_ref() {
+                        "raw" => BodyMode::Raw,
+                        "multipart" => BodyMode::Multipart,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "method" => {
+                    options.method = match value.to_ascii_uppercase().as_str() {
+                        method @ ("POST" | "PUT") => method.to_owned(),
+                        _ => return Err(invalid()),
+                    }
+                }
+                "auth" => {
+                    options.auth = match value.as_ref() {
+                        "auto" => AuthMode::Auto,
+                        "basic" => AuthMode::Basic,
+                        "bearer" => AuthMode::Bearer,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "redirects" => options.redirects = value.parse().map_err(|_| invalid())?,
+                "retries" => options.retries = value.parse().map_err(|_| invalid())?,
+                "verify" => options.verify = parse_bool(&value).ok_or_else(invalid)?,
+                "plainauth" => options.plain_auth = parse_bool(&value).ok_or_else(invalid)?,
+                _ => debug!("Ignoring unknown URI option {}", name),
+            }
+        }
+        Ok(options)
+    }
+}
+
+const OPTION_NAMES: &[&str] = &[
+    "body",
+    "method",
+    "auth",
+    "redirects",
+    "retries",
+    "verify",
+    "plainauth",
+];
+
+/// Upload URL: the device URI without credentials and backend options.
+fn upload_url(data: &BackendData) -> Url {
+    let mut url = data.printer_uri.without_credentials();
+    let query = url
+        .query_pairs()
+        .filter(|(name, _)| !OPTION_NAMES.contains(&name.as_ref()))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+    url
+}
+
+/// Map an HTTP status onto the backend exit code, `None` if the request should be retried.
+pub fn status_exit_code(status: u16) -> Option<ExitCode> {
+    match status {
+        200..=299 => Some(ExitCode::Success),
+        401 => Some(ExitCode::AuthRequired),
+        408 | 429 => None,
+        400..=499 => Some(ExitCode::CancelJob),
+        500..=599 => None,
+        _ => Some(ExitCode::ErrorPolicy),
+    }
+}
+
+/// Escape non-ASCII characters so that JSON can be sent in a header.
+fn ascii_json(json: &str) -> String {
+    let mut out = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            out.push(c);
+        } else {
+            let mut units = [0u16; 2];
+            for unit in c.encode_utf16(&mut units) {
+                out.push_str(&format!("\\u{:04x}", unit));
+            }
+        }
+    }
+    out
+}
+
+/// The job options as a JSON object, sorted by name.
+pub fn options_json(data: &BackendData) -> String {
+    let members = data
+        .options
+        .iter()
+        .collect::<BTreeMap<_, _>>()
+        .into_iter()
+        .map(|(name, value)| format!("{}:{}", json_string(name), json_string(value)))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", members.join(","))
+}
+
+fn metadata_json(data: &BackendData) -> String {
+    format!(
+        "{{\"job-id\":{},\"user\":{},\"title\":{},\"copies\":{},\"options\":{}}}",
+        data.job_id,
+        json_string(&data.user_name),
+        json_string(&data.title),
+        data.copies,
+        options_json(data)
+    )
+}
+
+/// Uploads jobs to an HTTP print gateway.
+#[derive(Default)]
+pub struct UploadBackend;
+
+impl UploadBackend {
+    pub fn new() -> UploadBackend {
+        UploadBackend
+    }
+
+    fn headers(data: &BackendData) -> Vec<(&'static str, String)> {
+        let encode = |value: &str| utf8_percent_encode(value, NON_ALPHANUMERIC).to_string();
+        vec![
+            ("X-Job-Id", data.job_id.to_string()),
+            ("X-Job-User", encode(&data.user_name)),
+            ("X-Job-Title", encode(&data.title)),
+            ("X-Job-Copies", data.copies.to_string()),
+            ("X-Job-Options", ascii_json(&options_json(data))),
+        ]
+    }
+
+    /// Authorization header for the job credentials, if there are any.
+    fn authorization(data: &BackendData, options: &UploadOptions) -> Option<String> {
+        let credentials = data.credentials.as_ref()?;
+        let username = credentials.username.as_deref();
+        let password = credentials.password.as_deref();
+        match (options.auth, username, password) {
+            (AuthMode::Auto | AuthMode::Basic, Some(username), Some(password)) => Some(format!(
+                "Basic {}",
+                STANDARD.encode(format!("{}:{}", username, password))
+            )),
+            (AuthMode::Auto, None, Some(token)) | (AuthMode::Bearer, _, Some(token)) => {
+                Some(format!("Bearer {}", token))
+            }
+            _ => None,
+        }
+    }
+
+    /// What to change so that credentials can be sent to the server, if they may not be.
+    fn credentials_blocked(url: &Url, options: &UploadOptions) -> Option<&'static str> {
+        if url.scheme() == "http" && !options.plain_auth {
+            Some("use https or set plainauth=true")
+        } else if url.scheme() == "https" && !options.verify {
+            Some("enable certificate verification")
+        } else {
+            None
+        }
+    }
+
+    /// Send the job once, without following redirects.
+    fn send(
+        client: &Client,
+        url: &Url,
+        headers: &[(&str, String)],
+        data: &BackendData,
+        options: &UploadOptions,
+    ) -> io::Result<Response> {
+        // retries and redirects send the data again
+        let mut file = File::open(data.job_source.path()?)?;
+        let size = file.metadata()?.len();
+        let content_type = data.content_type()?;
+
+        match options.body {
+            BodyMode::Raw => {
+                let body = Body {
+                    content_type,
+                    reader: &mut file,
+                    length: Some(size),
+                };
+                client.send(&options.method, url, headers, Some(body))
+            }
+            BodyMode::Multipart => {
+                let nanos = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_nanos())
+                    .unwrap_or_default();
+                let boundary = format!("=_job{}_{:x}", data.job_id, nanos);
+                let head = format!(
+                    "--{b}\r\n\
+                     Content-Disposition: form-data; name=\"metadata\"\r\n\
+                     Content-Type: application/json\r\n\r\n\
+                     {metadata}\r\n\
+                     --{b}\r\n\
+                     Content-Disposition: form-data; name=\"file\"; filename=\"job-{job}\"\r\n\
+                     Content-Type: {content_type}\r\n\r\n",
+                    b = boundary,
+                    metadata = metadata_json(data),
+                    job = data.job_id,
+                );
+                let tail = format!("\r\n--{}--\r\n", boundary);
+                let length = head.len() as u64 + size + tail.len() as u64;
+                let mut reader = Cursor::new(head).chain(file).chain(Cursor::new(tail));
+                let content_type = format!("multipart/form-data; boundary={}", boundary);
+                let body = Body {
+                    content_type: &content_type,
+                    reader: &mut reader,
+                    length: Some(length),
+                };
+                client.send(&options.method, url, headers, Some(body))
+            }
+        }
+    }
+
+    /// Upload the job, following redirects. Returns the final response.
+    /// `authorization` is only sent to the server of the device URI.
+    fn upload(
+        client: &Client,
+        data: &BackendData,
+        options: &UploadOptions,
+        authorization: Option<&str>,
+    ) -> io::Result<Response> {
+        let mut url = upload_url(data);
+        let origin = url.origin();
+        let mut headers = UploadBackend::headers(data);
+        if let Some(authorization) = authorization {
+            headers.push(("Authorization", authorization.to_owned()));
+        }
+        let mut redirects = 0;
+
+        loop {
+            info!("Uploading job to {}", redact(&url));
+            let response = UploadBackend::send(client, &url, &headers, data, options)?;
+            let location = match response.status {
+                301 | 302 | 303 | 307 | 308 => response.header("Location"),
+                _ => None,
+            };
+            let location = match location {
+                Some(location) if redirects < options.redirects => location,
+                _ => return Ok(response),
+            };
+            if !matches!(response.status, 307 | 308) {
+                error!(
+                    "Server redirected the upload to {} with status {}, \
+                     which does not preserve the job data",
+                    location, response.status
+                );
+                return Ok(response);
+            }
+
+            url = url.join(location).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("bad redirect: {}", e))
+            })?;
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("redirect to unsupported URL {}", redact(&url)),
+                ));
+            }
+            // credentials are only sent to the original server
+            if url.origin() != origin {
+                headers.retain(|(name, _)| *name != "Authorization");
+            }
+            redirects += 1;
+            debug!("Redirected to {}", redact(&url));
+        }
+    }
+}
+
+impl Backend for UploadBackend {
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    fn description(&self) -> &str {
+        "HTTP Upload"
+    }
+
+    fn discover(&self) -> Vec<DeviceInfo> {
+        ["http", "https"]
+            .iter()
+            .map(|scheme| {
+                let info = format!("HTTP Upload ({})", scheme);
+                scheme_device(DeviceClass::Network, scheme, &info)
+            })
+            .collect()
+    }
+
+    fn submit_job(&mut self, data: &BackendData) -> Result<ExitCode> {
+        if !matches!(data.printer_uri.scheme(), "http" | "https") {
+            return Err(BackendError::NoUri);
+        }
+        let options = UploadOptions::from_uri(&data.printer_uri)?;
+        let mut client = Client::new();
+        client.accept_invalid_certs = !options.verify;
+        let blocked = UploadBackend::credentials_blocked(&upload_url(data), &options);
+        // a server asking for withheld credentials stops the queue
+        let authorization =
+            UploadBackend::authorization(data, &options).filter(|_| blocked.is_none());
+
+        let mut delay = Duration::from_secs(5);
+        let mut attempt = 0;
+        loop {
+            let result = UploadBackend::upload(&client, data, &options, authorization.as_deref());
+            let exit_code = match result {
+                Ok(response) => {
+                    debug!("Server replied {} {}", response.status, response.reason);
+                    match status_exit_code(response.status) {
+                        Some(ExitCode::Success) => {
+                            info!("Job uploaded");
+                            return Ok(ExitCode::Success);
+                        }
+                        Some(ExitCode::AuthRequired) => {
+                            if let Some(fix) = blocked {
+                                error!("Server requires authentication, {}", fix);
+                                return Ok(ExitCode::StopQueue);
+                            }
+                            error!("Server requires authentication");
+                            return Err(BackendError::AuthRequired(vec![
+                                AuthInfo::Username,
+                                AuthInfo::Password,
+                            ]));
+                        }
+                        Some(code) => {
+                            error!(
+                                "Server rejected the job: {} {}",
+                                response.status, response.reason
+                            );
+                            return Ok(code);
+                        }
+                        None => {
+                            warn!("Server error: {} {}", response.status, response.reason);
+                            ExitCode::ErrorPolicy
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Unable to upload job: {}", e);
+                    ExitCode::ErrorPolicy
+                }
+            };
+
+            if attempt >= options.retries {
+                error!("Giving up after {} attempts", attempt + 1);
+                return Ok(exit_code);
+            }
+            attempt += 1;
+            info!("Retrying in {} seconds", delay.as_secs());
+            if !sleep_unless_cancelled(delay) {
+                return Ok(exit_code);
+            }
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    /// Request received by the fake server.
+    struct Received {
+        request_line: String,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    /// Fake HTTP server sending the given responses, one per connection.
+    fn fake_server(responses: Vec<String>) -> (u16, thread::JoinHandle<Vec<Received>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut received = Vec::new();
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut writer = stream.try_clone().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut headers = HashMap::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(':') {
+                        Some((name, value)) => {
+                            headers.insert(name.to_ascii_lowercase(), value.trim().to_owned())
+                        }
+                        None => break,
+                    };
+                }
+                let length = headers
+                    .get("content-length")
+                    .and_then(|length| length.parse().ok())
+                    .unwrap_or_default();
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+                writer.write_all(response.as_bytes()).unwrap();
+                received.push(Received {
+                    request_line: request_line.trim_end().to_owned(),
+                    headers,
+                    body,
+                });
+            }
+            received
+        });
+        (port, server)
+    }
+
+    fn reply(status: &str, headers: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: 0\r\n\r\n",
+            status, headers
+        )
+    }
+
+    fn job(uri: &str) -> (BackendData, NamedTempFile) {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"%PDF-1.4\n%test\n").unwrap();
+        let env = HashMap::from([
+            (String::from("DEVICE_URI"), uri.to_owned()),
+            (String::from("AUTH_USERNAME"), String::from("alice")),
+            (String::from("AUTH_PASSWORD"), String::from("secret")),
+        ]);
+        let args = [
+            "http",
+            "9",
+            "alice",
+            "report",
+            "1",
+            "duplex=true",
+            file.path().to_str().unwrap(),
+        ]
+        .map(String::from);
+        (BackendData::from_args(&args, &env).unwrap(), file)
+    }
+
+    const ALICE: &str = "Basic YWxpY2U6c2VjcmV0";
+
+    #[test]
+    fn raw_body() {
+        let (port, server) = fake_server(vec![reply("200 OK", "")]);
+        let (data, _file) = job(&format!("http://127.0.0.1:{}/jobs?queue=a", port));
+
+        let code = UploadBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = server.join().unwrap();
+        let request = &received[0];
+        assert_eq!(request.request_line, "POST /jobs?queue=a HTTP/1.1");
+        assert_eq!(request.headers["content-type"], "application/pdf");
+        assert_eq!(request.headers["x-job-id"], "9");
+        assert_eq!(request.headers["x-job-options"], r#"{"duplex":"true"}"#);
+        assert_eq!(request.body, b"%PDF-1.4\n%test\n");
+        // no credentials over plain HTTP without plainauth
+        assert!(!request.headers.contains_key("authorization"));
+    }
+
+    #[test]
+    fn multipart_body() {
+        let (port, server) = fake_server(vec![reply("201 Created", "")]);
+        let (data, _file) = job(&format!(
+            "http://127.0.0.1:{}/jobs?body=multipart&method=put",
+            port
+        ));
+
+        let code = UploadBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = server.join().unwrap();
+        let request = &received[0];
+        assert_eq!(request.request_line, "PUT /jobs HTTP/1.1");
+        let content_type = &request.headers["content-type"];
+        let boundary = content_type
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap();
+        let body = String::from_utf8(request.body.clone()).unwrap();
+        assert!(body.starts_with(&format!("--{}\r\n", boundary)));
+        assert!(body.contains(
+            r#"{"job-id":9,"user":"alice","title":"report","copies":1,"options":{"duplex":"true"}}"#
+        ));
+        assert!(body.contains("Content-Type: application/pdf\r\n\r\n%PDF-1.4\n%test\n\r\n"));
+        assert!(body.ends_with(&format!("\r\n--{}--\r\n", boundary)));
+    }
+
+    #[test]
+    fn authentication() {
+        let (port, server) = fake_server(vec![reply("401 Unauthorized", "")]);
+        let (data, _file) = job(&format!("http://127.0.0.1:{}/jobs?plainauth=true", port));
+
+        let result = UploadBackend::new().submit_job(&data);
+        assert!(matches!(result, Err(BackendError::AuthRequired(_))));
+        let received = server.join().unwrap();
+        assert_eq!(received[0].headers["authorization"], ALICE);
+
+        // credentials are withheld, asking for them cannot succeed
+        let (port, server) = fake_server(vec![reply("401 Unauthorized", "")]);
+        let (data, _file) = job(&format!("http://127.0.0.1:{}/jobs", port));
+
+        let code = UploadBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::StopQueue);
+        assert!(!server.join().unwrap()[0]
+            .headers
+            .contains_key("authorization"));
+    }
+
+    #[test]
+    fn client_error_cancels() {
+        let (port, server) = fake_server(vec![reply("404 Not Found", "")]);
+        let (data, _file) = job(&format!("http://127.0.0.1:{}/jobs", port));
+
+        let code = UploadBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::CancelJob);
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn server_error_retried() {
+        let responses = vec![reply("503 Service Unavailable", ""), reply("200 OK", "")];
+        let (port, server) = fake_server(responses);
+        let (data, _file) = job(&format!("http://127.0.0.1:{}/jobs", port));
+
+        let code = UploadBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = server.join().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].body, b"%PDF-1.4\n%test\n");
+    }
+
+    #[test]
+    fn cross_origin_redirect() {
+        let (target, target_server) = fake_server(vec![reply("200 OK", "")]);
+        let location = format!("Location: http://127.0.0.1:{}/upload\r\n", target);
+        let (port, server) = fake_server(vec![reply("307 Temporary Redirect", &location)]);
+        let (data, _file) = job(&format!("http://127.0.0.1:{}/jobs?plainauth=true", port));
+
+        let code = UploadBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let first = &server.join().unwrap()[0];
+        assert_eq!(first.headers["authorization"], ALICE);
+        let second = &target_server.join().unwrap()[0];
+        assert_eq!(second.request_line, "POST /upload HTTP/1.1");
+        assert_eq!(second.body, b"%PDF-1.4\n%test\n");
+        assert!(!second.headers.contains_key("authorization"));
+    }
+
+    #[test]
+    fn same_origin_redirect() {
+        let responses = vec![
+            reply("308 Permanent Redirect", "Location: /v2/jobs\r\n"),
+            reply("200 OK", ""),
+        ];
+        let (port, server) = fake_server(responses);
+        let (data, _file) = job(&format!("http://127.0.0.1:{}/jobs?plainauth=true", port));
+
+        let code = UploadBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::Success);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[1].request_line, "POST /v2/jobs HTTP/1.1");
+        assert_eq!(received[1].headers["authorization"], ALICE);
+    }
+
+    #[test]
+    fn refuses_redirect_to_get() {
+        let responses = vec![reply("302 Found", "Location: /elsewhere\r\n")];
+        let (port, server) = fake_server(responses);
+        let (data, _file) = job(&format!("http://127.0.0.1:{}/jobs", port));
+
+        let code = UploadBackend::new().submit_job(&data).unwrap();
+        assert_eq!(code, ExitCode::ErrorPolicy);
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+}
@@ -1,28 +1,283 @@
 use std::{
+    cell::RefCell,
     collections::HashMap,
-    env,
-    io::{self, Write},
+    env, fmt,
+    fs::{self, File},
+    io::{self, Read, Write},
+    os::unix::io::RawFd,
     path::{Path, PathBuf},
     process::exit,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock, PoisonError,
+    },
+    thread,
 };
 
 use log::{error, info, LevelFilter};
 use tempfile::NamedTempFile;
-use url::Url;
 
-const NAME: &str = "testbackend";
-const DESCRIPTION: &str = "CUPS backend in Rust";
+use crate::{
+    auth::{AuthInfo, Credentials},
+    backchannel::BackChannel,
+    device::{DeviceClass, DeviceInfo},
+    environment::CupsEnvironment,
+    fd,
+    format::{DocumentFormat, SNIFF_LEN},
+    options::parse_options,
+    sidechannel::{DefaultHandler, SideChannel, SideChannelHandler},
+    status::{MessageLevel, StatusWriter},
+    uri::DeviceUri,
+};
+
+/// Set by the SIGTERM handler installed by [`CupsBackend::run`].
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Job processed by a thread.
+#[derive(Clone)]
+struct CurrentJob {
+    id: JobId,
+    cancel: CancelToken,
+    back_channel: Option<RawFd>,
+    side_channel: Option<RawFd>,
+}
+
+thread_local! {
+    /// Job being processed by this thread, used to tag log output and check for cancellation.
+    static CURRENT_JOB: RefCell<Option<CurrentJob>> = const { RefCell::new(None) };
+}
+
+/// Sets the job of the current thread, restoring the previous one when dropped.
+struct JobScope(Option<CurrentJob>);
+
+impl JobScope {
+    fn enter(job: Option<CurrentJob>) -> JobScope {
+        JobScope(CURRENT_JOB.replace(job))
+    }
+}
+
+impl Drop for JobScope {
+    fn drop(&mut self) {
+        CURRENT_JOB.set(self.0.take());
+    }
+}
+
+/// Spawn a thread working on the job of the calling thread, so that its log
+/// output is tagged with the same job ID and [`is_cancelled`] checks the same job.
+pub fn spawn_job_thread<F, T>(f: F) -> thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let job = CURRENT_JOB.with_borrow(Clone::clone);
+    thread::spawn(move || {
+        let _job = JobScope::enter(job);
+        f()
+    })
+}
+
+extern "C" fn on_sigterm(_: libc::c_int) {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// Returns true if the job processed by the current thread has been cancelled,
+/// either by SIGTERM from CUPS or through its [`CancelToken`].
+///
+/// Cancellation neither terminates the process nor reliably interrupts work in
+/// progress: backends must poll this while sending data or waiting for the
+/// device and return early, after which [`Backend::cancel_job`] is called.
+pub fn is_cancelled() -> bool {
+    CURRENT_JOB.with_borrow(|job| match job {
+        Some(job) => job.cancel.is_cancelled(),
+        None => CANCELLED.load(Ordering::SeqCst),
+    })
+}
+
+/// Cancellation state of a single job. Clones share the state, so the caller of
+/// [`CupsBackend::execute_with`] can keep one to cancel the job from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    /// Whether SIGTERM cancels the job as well.
+    signal: bool,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Token of a job run as a CUPS backend process, cancelled by SIGTERM.
+    fn signal() -> CancelToken {
+        CancelToken {
+            signal: true,
+            ..CancelToken::default()
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst) || (self.signal && CANCELLED.load(Ordering::SeqCst))
+    }
+}
+
+/// Per-job settings for [`CupsBackend::execute_with`].
+#[derive(Debug, Clone, Default)]
+pub struct JobContext {
+    /// Cancels the job, see [`is_cancelled`].
+    pub cancel: CancelToken,
+    /// Descriptor returned by [`BackChannel::open`](crate::backchannel::BackChannel::open), none by default.
+    pub back_channel: Option<RawFd>,
+    /// Descriptor served by [`SideChannel::open`](crate::sidechannel::SideChannel::open), none by default.
+    pub side_channel: Option<RawFd>,
+}
+
+/// Back-channel descriptor of the job processed by the current thread.
+pub(crate) fn current_back_channel() -> Option<RawFd> {
+    CURRENT_JOB.with_borrow(|job| job.as_ref().and_then(|job| job.back_channel))
+}
+
+/// Side-channel descriptor of the job processed by the current thread.
+pub(crate) fn current_side_channel() -> Option<RawFd> {
+    CURRENT_JOB.with_borrow(|job| job.as_ref().and_then(|job| job.side_channel))
+}
+
+/// CUPS job ID, the first backend argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct JobId(pub u32);
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
+/// Where the job data comes from.
 pub enum JobSource {
+    /// File given on the command line.
     JobFile(PathBuf),
+    /// Data already spooled into a temporary file.
     TempFile(NamedTempFile),
+    /// Data read as it arrives, usually from stdin. It is spooled into a
+    /// temporary file only if a backend asks for a path or its size.
+    Stream(JobStream),
+}
+
+/// Job data which has not been spooled yet.
+pub struct JobStream {
+    pending: Mutex<Option<Pending>>,
+    spooled: OnceLock<NamedTempFile>,
+}
+
+/// Unread part of a stream, with the bytes already peeked at.
+struct Pending {
+    head: Vec<u8>,
+    reader: Box<dyn Read + Send>,
+}
+
+impl JobStream {
+    pub fn new<R: Read + Send + 'static>(reader: R) -> JobStream {
+        JobStream {
+            pending: Mutex::new(Some(Pending {
+                head: Vec::new(),
+                reader: Box::new(reader),
+            })),
+            spooled: OnceLock::new(),
+        }
+    }
+
+    fn take_reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .ok_or_else(consumed)?;
+        Ok(Box::new(
+            io::Cursor::new(pending.head).chain(pending.reader),
+        ))
+    }
+
+    fn peek(&self, len: usize) -> io::Result<Vec<u8>> {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let pending = pending.as_mut().ok_or_else(consumed)?;
+        if pending.head.len() < len {
+            let more = (len - pending.head.len()) as u64;
+            (&mut pending.reader)
+                .take(more)
+                .read_to_end(&mut pending.head)?;
+        }
+        Ok(pending.head[..len.min(pending.head.len())].to_vec())
+    }
+
+    fn spool(&self) -> io::Result<&NamedTempFile> {
+        if let Some(temp) = self.spooled.get() {
+            return Ok(temp);
+        }
+        let mut reader = self.take_reader()?;
+        let mut temp = NamedTempFile::new()?;
+        io::copy(&mut reader, &mut temp)?;
+        Ok(self.spooled.get_or_init(|| temp))
+    }
+}
+
+fn consumed() -> io::Error {
+    io::Error::other("job data has already been read")
 }
 
 impl JobSource {
-    pub fn path(&self) -> &Path {
+    /// Path of the job data, spooling a stream first.
+    pub fn path(&self) -> io::Result<&Path> {
         match self {
-            JobSource::JobFile(ref path) => path,
-            JobSource::TempFile(ref temp) => temp.path(),
+            JobSource::JobFile(ref path) => Ok(path),
+            JobSource::TempFile(ref temp) => Ok(temp.path()),
+            JobSource::Stream(ref stream) => Ok(stream.spool()?.path()),
+        }
+    }
+
+    /// Returns true if the data is in a file and can be read more than once.
+    pub fn is_spooled(&self) -> bool {
+        match self {
+            JobSource::JobFile(_) | JobSource::TempFile(_) => true,
+            JobSource::Stream(ref stream) => stream.spooled.get().is_some(),
+        }
+    }
+
+    /// Size of the job data, `None` for a stream which has not been spooled.
+    pub fn size(&self) -> io::Result<Option<u64>> {
+        if self.is_spooled() {
+            Ok(Some(fs::metadata(self.path()?)?.len()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Read up to `len` bytes from the start of the job data without consuming them.
+    pub fn peek(&self, len: usize) -> io::Result<Vec<u8>> {
+        match self {
+            JobSource::Stream(ref stream) if !self.is_spooled() => stream.peek(len),
+            _ => {
+                let mut head = Vec::new();
+                self.reader()?.take(len as u64).read_to_end(&mut head)?;
+                Ok(head)
+            }
+        }
+    }
+
+    /// Guess the format of the job data from its first bytes.
+    pub fn sniff_format(&self) -> io::Result<Option<DocumentFormat>> {
+        Ok(DocumentFormat::sniff(&self.peek(SNIFF_LEN)?))
+    }
+
+    /// Open the job data for reading. A stream which has not been spooled can
+    /// only be read once, call [`JobSource::path`] first to read it again.
+    pub fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            JobSource::Stream(ref stream) if !self.is_spooled() => stream.take_reader(),
+            _ => Ok(Box::new(File::open(self.path()?)?)),
         }
     }
 }
@@ -43,6 +298,12 @@ pub enum BackendError {
     NoArgs,
     BadArgs,
     NoUri,
+    /// The device requires credentials of the given kinds.
+    AuthRequired(Vec<AuthInfo>),
+    InvalidOption {
+        name: String,
+        value: String,
+    },
     IOError(io::Error),
 }
 
@@ -51,6 +312,7 @@ impl BackendError {
         match *self {
             BackendError::NoArgs => ExitCode::Success,
             BackendError::BadArgs => ExitCode::ErrorPolicy,
+            BackendError::AuthRequired(_) => ExitCode::AuthRequired,
             _ => ExitCode::CancelJob,
         }
     }
@@ -63,35 +325,78 @@ impl From<io::Error> for BackendError {
 }
 
 pub struct BackendData {
-    pub printer_uri: Url,
+    pub job_id: JobId,
+    pub printer_uri: DeviceUri,
     pub user_name: String,
     pub title: String,
     pub copies: u32,
     pub options: HashMap<String, String>,
     pub job_source: JobSource,
+    pub environment: CupsEnvironment,
+    pub credentials: Option<Credentials>,
+    /// Cancellation state of the job, checked by [`is_cancelled`] on the threads processing it.
+    pub cancel: CancelToken,
 }
 
 pub type Result<T> = std::result::Result<T, BackendError>;
 
 impl BackendData {
-    fn parse_args() -> Result<BackendData> {
-        let args: Vec<_> = env::args().collect();
+    /// Parse the process arguments and environment.
+    pub fn parse_args() -> Result<BackendData> {
+        let args: Vec<String> = env::args().collect();
+        let vars: HashMap<String, String> = env::vars().collect();
+        BackendData::from_args(&args, &vars)
+    }
+
+    /// Format of the job data. The content type set by the scheduler takes
+    /// precedence if it names a known format, otherwise the data is sniffed.
+    /// `CONTENT_TYPE` is only used if `FINAL_CONTENT_TYPE` is not set, as it
+    /// describes the submitted document rather than the output of the filters.
+    pub fn document_format(&self) -> io::Result<Option<DocumentFormat>> {
+        let declared = self
+            .environment
+            .final_content_type
+            .as_deref()
+            .or(self.environment.content_type.as_deref())
+            .and_then(DocumentFormat::from_mime_type);
+        match declared {
+            Some(format) => Ok(Some(format)),
+            None => self.job_source.sniff_format(),
+        }
+    }
 
+    /// MIME type of the job data as determined by [`document_format`](Self::document_format),
+    /// `application/octet-stream` if the format is unknown.
+    pub fn content_type(&self) -> io::Result<&'static str> {
+        Ok(self
+            .document_format()?
+            .map(DocumentFormat::mime_type)
+            .unwrap_or("application/octet-stream"))
+    }
+
+    /// Parse an explicit backend argument list (including `argv[0]`) and environment.
+    /// If no file argument is given, the job data is streamed from stdin.
+    pub fn from_args(args: &[String], vars: &HashMap<String, String>) -> Result<BackendData> {
         if args.len() < 2 {
             return Err(BackendError::NoArgs);
         } else if args.len() != 6 && args.len() != 7 {
             return Err(BackendError::BadArgs);
         }
 
-        let printer_uri = if let Some(uri) = env::var("DEVICE_URI")
-            .ok()
-            .and_then(|uri| Url::parse(&uri).ok())
+        let printer_uri = if let Some(uri) = vars
+            .get("DEVICE_URI")
+            .and_then(|uri| DeviceUri::parse(uri).ok())
         {
             uri
         } else {
             return Err(BackendError::NoUri);
         };
 
+        let job_id = match args[1].parse::<u32>() {
+            Ok(id) if id > 0 => JobId(id),
+            _ => return Err(BackendError::BadArgs),
+        };
+
         let user_name = args[2].clone();
 
         let title = if !args[3].is_empty() {
@@ -105,81 +410,368 @@ impl BackendData {
             String::from("untitled")
         };
 
-        let copies = args[4].parse::<u32>().unwrap_or(1);
+        let copies = match args[4].parse::<u32>() {
+            Ok(copies) if copies > 0 => copies,
+            _ => return Err(BackendError::BadArgs),
+        };
 
-        let mut options = HashMap::new();
-
-        for opt in args[5].split_whitespace() {
-            let mut kv = opt.splitn(2, '=');
-            if let Some(k) = kv.next() {
-                let v = kv.next().unwrap_or("true");
-                options.insert(k.to_lowercase(), v.to_lowercase());
-            }
-        }
+        let options = parse_options(&args[5])
+            .into_iter()
+            .map(|(name, value)| (name.to_lowercase(), value))
+            .collect();
 
         let job_source = if args.len() >= 7 {
             JobSource::JobFile(PathBuf::from(&args[6]))
         } else {
-            let mut tmp = tempfile::NamedTempFile::new()?;
-            io::copy(&mut io::stdin(), &mut tmp)?;
-            JobSource::TempFile(tmp)
+            JobSource::Stream(JobStream::new(io::stdin()))
+        };
+
+        // AUTH_* variables take precedence over the userinfo in the device URI
+        let credentials = match (Credentials::from_vars(vars), printer_uri.credentials()) {
+            (Some(env), Some(uri)) => Some(env.merge(uri)),
+            (env, uri) => env.or(uri),
         };
 
         Ok(BackendData {
+            job_id,
             printer_uri,
             user_name,
             title,
             copies,
             options,
             job_source,
+            environment: CupsEnvironment::from_vars(vars),
+            credentials,
+            cancel: CancelToken::new(),
         })
     }
 }
 
-#[derive(Default)]
-pub struct CupsBackend;
+/// How multiple copies of a job are produced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopiesStrategy {
+    /// The backend produces `BackendData::copies` copies itself.
+    Backend,
+    /// The job is submitted once per copy, with `BackendData::copies` set to 1.
+    Resend,
+}
 
-impl CupsBackend {
+/// Backend implementation driven by [`CupsBackend`].
+pub trait Backend {
+    /// Backend name, also used as the device URI scheme.
+    fn name(&self) -> &str;
+
+    /// Human-readable backend description.
+    fn description(&self) -> &str;
+
+    /// Device discovery, called when the backend is run without arguments.
+    /// The default implementation advertises a single direct device.
+    fn discover(&self) -> Vec<DeviceInfo> {
+        let mut device = DeviceInfo::new(DeviceClass::Direct, format!("{}://", self.name()));
+        device.info = self.description().to_owned();
+        vec![device]
+    }
+
+    /// How the copies argument is honoured, [`CopiesStrategy::Backend`] by default.
+    fn copies_strategy(&self) -> CopiesStrategy {
+        CopiesStrategy::Backend
+    }
+
+    /// Send the job to the device. Implementations must check [`is_cancelled`]
+    /// regularly and return early once the job has been cancelled.
+    fn submit_job(&mut self, data: &BackendData) -> Result<ExitCode>;
+
+    /// Handler for side-channel requests from the filters, answered on a separate
+    /// thread while the job is processed. The default handler describes an online,
+    /// unidirectional device; `None` leaves the side-channel to the backend.
+    fn side_channel_handler(&mut self) -> Option<Box<dyn SideChannelHandler + Send>> {
+        Some(Box::new(DefaultHandler))
+    }
+
+    /// Called after `submit_job` returns if the job was cancelled by CUPS.
+    fn cancel_job(&mut self, _data: &BackendData) -> Result<ExitCode> {
+        Ok(ExitCode::CancelJob)
+    }
+}
+
+pub struct CupsBackend<B: Backend> {
+    backend: B,
+}
+
+impl<B: Backend> CupsBackend<B> {
     fn advertise(&self) {
-        println!("direct {}:// \"Unknown\" \"{}\"", NAME, DESCRIPTION);
+        for device in self.backend.discover() {
+            println!("{}", device);
+        }
     }
 
-    fn usage(&self) {
-        eprintln!(
-            "Usage: {} job-id user title copies options [file]",
-            env::args().next().unwrap()
-        );
+    fn usage(&self, program: &str) {
+        eprintln!("Usage: {} job-id user title copies options [file]", program);
     }
 
-    pub fn new() -> CupsBackend {
-        CupsBackend::default()
+    pub fn new(backend: B) -> CupsBackend<B> {
+        CupsBackend { backend }
     }
 
-    pub fn run(&self) {
+    /// Run the backend with the process arguments and environment and exit with the resulting code.
+    pub fn run(&mut self) {
+        // before opening any file, which could reuse a free channel descriptor number
+        let channel = |fd| Some(fd).filter(|&fd| fd::is_open(fd));
+        let context = JobContext {
+            cancel: CancelToken::signal(),
+            back_channel: channel(BackChannel::FD),
+            side_channel: channel(SideChannel::FD),
+        };
+
         env::set_var("RUST_LOG", "debug");
 
         let mut builder = env_logger::builder();
-        builder.format(|buf, record| writeln!(buf, "{}: {}", record.level(), record.args()));
+        builder.format(|buf, record| {
+            let level = MessageLevel::from(record.level());
+            match CURRENT_JOB.with_borrow(|job| job.as_ref().map(|job| job.id)) {
+                None => writeln!(buf, "{}: {}", level, record.args()),
+                Some(job) => writeln!(buf, "{}: [Job {}] {}", level, job, record.args()),
+            }
+        });
         let _ = log::set_boxed_logger(Box::new(builder.build()));
         log::set_max_level(LevelFilter::Debug);
 
-        let code = match BackendData::parse_args() {
-            Ok(data) => self.process_data(data),
+        // without SA_RESTART: a system call blocked in the main thread fails with EINTR,
+        // which the channel descriptor helpers report once the job is cancelled.
+        // Network backends use socket timeouts and check for cancellation in between.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_sigterm as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGTERM, &action, std::ptr::null_mut());
+        }
+
+        let args: Vec<String> = env::args().collect();
+        let vars: HashMap<String, String> = env::vars().collect();
+
+        let code = self.execute_with(&args, &vars, context);
+        exit(code as i32);
+    }
+
+    /// Run the backend with an explicit argument list and environment, returning the exit code.
+    /// Unlike [`run`](Self::run) this neither installs a logger nor exits the process,
+    /// the job is not cancelled by SIGTERM and has no back- or side-channel.
+    pub fn execute(&mut self, args: &[String], vars: &HashMap<String, String>) -> ExitCode {
+        self.execute_with(args, vars, JobContext::default())
+    }
+
+    /// Like [`execute`](Self::execute), with the cancellation token and channel
+    /// descriptors of the job given explicitly.
+    pub fn execute_with(
+        &mut self,
+        args: &[String],
+        vars: &HashMap<String, String>,
+        context: JobContext,
+    ) -> ExitCode {
+        let data = BackendData::from_args(args, vars).map(|mut data| {
+            data.cancel = context.cancel;
+            data
+        });
+        // log output and cancellation checks of this thread refer to the job,
+        // including the errors logged below
+        let _job = data.as_ref().ok().map(|data| {
+            JobScope::enter(Some(CurrentJob {
+                id: data.job_id,
+                cancel: data.cancel.clone(),
+                back_channel: context.back_channel,
+                side_channel: context.side_channel,
+            }))
+        });
+        match data.and_then(|data| self.process_data(data)) {
+            Ok(code) => code,
             Err(err) => {
                 match err {
                     BackendError::NoArgs => self.advertise(),
-                    BackendError::BadArgs => self.usage(),
+                    BackendError::BadArgs => {
+                        self.usage(args.first().map(String::as_str).unwrap_or_default())
+                    }
                     BackendError::NoUri => error!("No printer URI"),
+                    BackendError::AuthRequired(ref auth_info) => {
+                        error!("Authentication required");
+                        let _ = StatusWriter::stderr().auth_info_required(auth_info);
+                    }
+                    BackendError::InvalidOption {
+                        ref name,
+                        ref value,
+                    } => error!("Invalid value for option {}: {}", name, value),
                     BackendError::IOError(ref e) => error!("{}", e),
                 }
                 err.to_exit_code()
             }
-        };
-        exit(code as i32);
+        }
     }
 
-    fn process_data(&self, data: BackendData) -> ExitCode {
+    fn process_data(&mut self, mut data: BackendData) -> Result<ExitCode> {
         info!("Processing job: {}", data.title);
-        ExitCode::Success
+        let stop = Arc::new(AtomicBool::new(false));
+        let side = SideChannel::open()
+            .zip(self.backend.side_channel_handler())
+            .map(|(side, handler)| side.serve(handler, stop.clone()));
+        let code = match self.backend.copies_strategy() {
+            CopiesStrategy::Backend => self.backend.submit_job(&data),
+            CopiesStrategy::Resend => self.resend(&mut data),
+        };
+        stop.store(true, Ordering::SeqCst);
+        if let Some(side) = side {
+            let _ = side.join();
+        }
+        let code = code?;
+        if is_cancelled() {
+            info!("Job cancelled");
+            self.backend.cancel_job(&data)
+        } else {
+            Ok(code)
+        }
+    }
+
+    fn resend(&mut self, data: &mut BackendData) -> Result<ExitCode> {
+        let copies = data.copies;
+        data.copies = 1;
+        if copies > 1 {
+            // every copy reads the data again
+            data.job_source.path()?;
+        }
+
+        let mut status = StatusWriter::stderr().with_job_id(data.job_id);
+        for copy in 1..=copies {
+            if copies > 1 {
+                status.info(&format!("Sending copy {} of {}", copy, copies))?;
+            }
+            let code = self.backend.submit_job(data)?;
+            if code != ExitCode::Success || is_cancelled() {
+                return Ok(code);
+            }
+            if let JobSource::JobFile(_) = data.job_source {
+                status.page(1, 1)?;
+            }
+            status.progress(copy * 100 / copies)?;
+        }
+        Ok(ExitCode::Success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::{io::AsRawFd, net::UnixStream};
+
+    use super::*;
+
+    #[test]
+    fn job_threads_inherit_the_job() {
+        let cancel = CancelToken::new();
+        let _job = JobScope::enter(Some(CurrentJob {
+            id: JobId(42),
+            cancel: cancel.clone(),
+            back_channel: None,
+            side_channel: None,
+        }));
+        let current = || CURRENT_JOB.with_borrow(|job| job.as_ref().map(|job| job.id));
+        assert_eq!(spawn_job_thread(current).join().unwrap(), Some(JobId(42)));
+        assert_eq!(thread::spawn(current).join().unwrap(), None);
+
+        assert!(!spawn_job_thread(is_cancelled).join().unwrap());
+        cancel.cancel();
+        assert!(is_cancelled());
+        assert!(spawn_job_thread(is_cancelled).join().unwrap());
+        assert!(!thread::spawn(is_cancelled).join().unwrap());
+    }
+
+    /// Reports through the exit code whether the job has a back-channel.
+    struct ChannelProbe;
+
+    impl Backend for ChannelProbe {
+        fn name(&self) -> &str {
+            "probe"
+        }
+
+        fn description(&self) -> &str {
+            "Channel probe"
+        }
+
+        fn submit_job(&mut self, _data: &BackendData) -> Result<ExitCode> {
+            Ok(match BackChannel::open() {
+                Some(_) => ExitCode::Success,
+                None => ExitCode::HoldJob,
+            })
+        }
+
+        fn side_channel_handler(&mut self) -> Option<Box<dyn SideChannelHandler + Send>> {
+            None
+        }
+    }
+
+    #[test]
+    fn channels_are_explicit() {
+        let file = NamedTempFile::new().unwrap();
+        let args = ["probe", "1", "alice", "title", "1", ""]
+            .map(String::from)
+            .into_iter()
+            .chain([file.path().to_string_lossy().into_owned()])
+            .collect::<Vec<_>>();
+        let vars = HashMap::from([(String::from("DEVICE_URI"), String::from("probe://"))]);
+        let mut backend = CupsBackend::new(ChannelProbe);
+
+        // descriptor 3 of the test process is not a channel
+        assert_eq!(backend.execute(&args, &vars), ExitCode::HoldJob);
+
+        let (channel, _filter) = UnixStream::pair().unwrap();
+        let context = JobContext {
+            back_channel: Some(channel.as_raw_fd()),
+            ..JobContext::default()
+        };
+        assert_eq!(
+            backend.execute_with(&args, &vars, context),
+            ExitCode::Success
+        );
+    }
+
+    #[test]
+    fn document_format_precedence() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"%PDF-1.4\n").unwrap();
+        let args = ["probe", "1", "alice", "title", "1", ""]
+            .map(String::from)
+            .into_iter()
+            .chain([file.path().to_string_lossy().into_owned()])
+            .collect::<Vec<_>>();
+        let format = |vars: &[(&str, &str)]| {
+            let mut vars = vars
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+                .collect::<HashMap<_, _>>();
+            vars.insert(String::from("DEVICE_URI"), String::from("probe://"));
+            BackendData::from_args(&args, &vars)
+                .unwrap()
+                .document_format()
+                .unwrap()
+        };
+
+        // sniffed without a content type, or with one that is not known
+        assert_eq!(format(&[]), Some(DocumentFormat::Pdf));
+        let unknown = [("FINAL_CONTENT_TYPE", "application/vnd.cups-raster")];
+        assert_eq!(format(&unknown), Some(DocumentFormat::Pdf));
+        // a known content type wins over the data
+        let text = [("CONTENT_TYPE", "text/plain")];
+        assert_eq!(format(&text), Some(DocumentFormat::Text));
+        // the final content type describes the data the backend gets
+        let both = [
+            ("CONTENT_TYPE", "text/plain"),
+            ("FINAL_CONTENT_TYPE", "application/postscript"),
+        ];
+        assert_eq!(format(&both), Some(DocumentFormat::PostScript));
+    }
+
+    #[test]
+    fn jobs_are_cancelled_separately() {
+        let first = CancelToken::new();
+        let second = CancelToken::new();
+        first.clone().cancel();
+        assert!(first.is_cancelled());
+        assert!(!second.is_cancelled());
     }
 }
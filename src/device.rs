@@ -0,0 +1,90 @@
+use std::fmt;
+
+/// Device class reported in discovery mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceClass {
+    Direct,
+    File,
+    Network,
+    Serial,
+}
+
+impl DeviceClass {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DeviceClass::Direct => "direct",
+            DeviceClass::File => "file",
+            DeviceClass::Network => "network",
+            DeviceClass::Serial => "serial",
+        }
+    }
+}
+
+impl fmt::Display for DeviceClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Device found during discovery, printed as one `lpinfo -v` line:
+///
+/// `class uri "make-and-model" "info" ["device-id" ["location"]]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub device_class: DeviceClass,
+    pub uri: String,
+    pub make_and_model: String,
+    pub info: String,
+    /// IEEE 1284 device ID, e.g. `MFG:HP;MDL:LaserJet 4000;CMD:PCL,PJL;`
+    pub device_id: String,
+    pub location: String,
+}
+
+impl DeviceInfo {
+    pub fn new<S: Into<String>>(device_class: DeviceClass, uri: S) -> DeviceInfo {
+        DeviceInfo {
+            device_class,
+            uri: uri.into(),
+            make_and_model: String::from("Unknown"),
+            info: String::new(),
+            device_id: String::new(),
+            location: String::new(),
+        }
+    }
+}
+
+struct Quoted<'a>(&'a str);
+
+impl fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' | '\\' => write!(f, "\\{}", c)?,
+                c if c.is_control() => f.write_str(" ")?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        f.write_str("\"")
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.device_class,
+            self.uri.replace(char::is_whitespace, "%20"),
+            Quoted(&self.make_and_model),
+            Quoted(&self.info)
+        )?;
+        if !self.device_id.is_empty() || !self.location.is_empty() {
+            write!(f, " {}", Quoted(&self.device_id))?;
+        }
+        if !self.location.is_empty() {
+            write!(f, " {}", Quoted(&self.location))?;
+        }
+        Ok(())
+    }
+}
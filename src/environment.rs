@@ -0,0 +1,97 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::auth::AuthInfo;
+
+/// Value of `CUPS_FILETYPE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Document,
+    JobSheet,
+}
+
+/// Environment variables exported by the scheduler to the backend.
+/// Unset and empty variables are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CupsEnvironment {
+    /// MIME type of the job file, `CONTENT_TYPE`.
+    pub content_type: Option<String>,
+    /// MIME type of the data sent to the printer, `FINAL_CONTENT_TYPE`.
+    pub final_content_type: Option<String>,
+    /// Queue name, `PRINTER`.
+    pub printer: Option<String>,
+    pub printer_info: Option<String>,
+    pub printer_location: Option<String>,
+    /// Current printer-state-reasons, `PRINTER_STATE_REASONS`.
+    pub printer_state_reasons: Vec<String>,
+    /// Class name if the job was submitted to a class, `CLASS`.
+    pub class: Option<String>,
+    /// PPD file of the queue, `PPD`.
+    pub ppd: Option<PathBuf>,
+    pub charset: Option<String>,
+    pub lang: Option<String>,
+    pub server_root: Option<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+    pub cache_dir: Option<PathBuf>,
+    pub state_dir: Option<PathBuf>,
+    /// Scheduler address, `CUPS_SERVER`.
+    pub server: Option<String>,
+    pub encryption: Option<String>,
+    /// Authentication keywords the queue requires, `AUTH_INFO_REQUIRED`.
+    pub auth_info_required: Vec<AuthInfo>,
+    pub file_type: Option<FileType>,
+    /// Maximum length of a status message, `CUPS_MAX_MESSAGE`.
+    pub max_message: Option<usize>,
+    /// Scheduler name and version, `SOFTWARE`.
+    pub software: Option<String>,
+    pub tz: Option<String>,
+    pub user: Option<String>,
+}
+
+impl CupsEnvironment {
+    pub fn from_vars(vars: &HashMap<String, String>) -> CupsEnvironment {
+        let get = |name: &str| vars.get(name).filter(|v| !v.is_empty()).cloned();
+        let list = |name: &str| -> Vec<String> {
+            get(name)
+                .map(|v| {
+                    v.split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty() && *s != "none")
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        CupsEnvironment {
+            content_type: get("CONTENT_TYPE"),
+            final_content_type: get("FINAL_CONTENT_TYPE"),
+            printer: get("PRINTER"),
+            printer_info: get("PRINTER_INFO"),
+            printer_location: get("PRINTER_LOCATION"),
+            printer_state_reasons: list("PRINTER_STATE_REASONS"),
+            class: get("CLASS"),
+            ppd: get("PPD").map(PathBuf::from),
+            charset: get("CHARSET"),
+            lang: get("LANG"),
+            server_root: get("CUPS_SERVERROOT").map(PathBuf::from),
+            data_dir: get("CUPS_DATADIR").map(PathBuf::from),
+            cache_dir: get("CUPS_CACHEDIR").map(PathBuf::from),
+            state_dir: get("CUPS_STATEDIR").map(PathBuf::from),
+            server: get("CUPS_SERVER"),
+            encryption: get("CUPS_ENCRYPTION"),
+            auth_info_required: list("AUTH_INFO_REQUIRED")
+                .iter()
+                .filter_map(|keyword| keyword.parse().ok())
+                .collect(),
+            file_type: get("CUPS_FILETYPE").and_then(|t| match t.as_str() {
+                "document" => Some(FileType::Document),
+                "job-sheet" => Some(FileType::JobSheet),
+                _ => None,
+            }),
+            max_message: get("CUPS_MAX_MESSAGE").and_then(|v| v.parse().ok()),
+            software: get("SOFTWARE"),
+            tz: get("TZ"),
+            user: get("USER"),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Thin wrappers over the raw descriptor calls shared by the back- and side-channel.
+
+use std::{io, os::unix::io::RawFd, time::Duration};
+
+use crate::is_cancelled;
+
+pub(crate) fn is_open(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+}
+
+/// Whether a failed call should be repeated: calls interrupted by a signal are,
+/// unless the signal cancelled the job.
+fn retry(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::Interrupted && !is_cancelled()
+}
+
+/// Wait for the given events, `None` waits forever. Returns false on timeout.
+pub(crate) fn poll(
+    fd: RawFd,
+    events: libc::c_short,
+    timeout: Option<Duration>,
+) -> io::Result<bool> {
+    let timeout = match timeout {
+        Some(t) => t.as_millis().min(libc::c_int::MAX as u128) as libc::c_int,
+        None => -1,
+    };
+    let mut pfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    loop {
+        match unsafe { libc::poll(&mut pfd, 1, timeout) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if !retry(&err) {
+                    return Err(err);
+                }
+            }
+            0 => return Ok(false),
+            _ => return Ok(true),
+        }
+    }
+}
+
+pub(crate) fn read(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let err = io::Error::last_os_error();
+        if !retry(&err) {
+            return Err(err);
+        }
+    }
+}
+
+pub(crate) fn write(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
+    loop {
+        let n = unsafe { libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let err = io::Error::last_os_error();
+        if !retry(&err) {
+            return Err(err);
+        }
+    }
+}
+
+pub(crate) fn timed_out(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", what))
+}
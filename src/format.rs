@@ -0,0 +1,226 @@
+//! Document format detection.
+
+use crate::pjl::UEL;
+
+/// Number of bytes examined when sniffing the format of a job.
+pub const SNIFF_LEN: usize = 4096;
+
+/// Format of the job data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DocumentFormat {
+    Pdf,
+    PostScript,
+    Pcl,
+    PwgRaster,
+    /// Apple raster.
+    Urf,
+    Jpeg,
+    Text,
+}
+
+impl DocumentFormat {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            DocumentFormat::Pdf => "application/pdf",
+            DocumentFormat::PostScript => "application/postscript",
+            DocumentFormat::Pcl => "application/vnd.hp-PCL",
+            DocumentFormat::PwgRaster => "image/pwg-raster",
+            DocumentFormat::Urf => "image/urf",
+            DocumentFormat::Jpeg => "image/jpeg",
+            DocumentFormat::Text => "text/plain",
+        }
+    }
+
+    /// Parse a MIME type, ignoring case and parameters such as `charset`.
+    pub fn from_mime_type(mime_type: &str) -> Option<DocumentFormat> {
+        let mime_type = mime_type.split(';').next().unwrap_or_default().trim();
+        [
+            DocumentFormat::Pdf,
+            DocumentFormat::PostScript,
+            DocumentFormat::Pcl,
+            DocumentFormat::PwgRaster,
+            DocumentFormat::Urf,
+            DocumentFormat::Jpeg,
+            DocumentFormat::Text,
+        ]
+        .into_iter()
+        .find(|format| format.mime_type().eq_ignore_ascii_case(mime_type))
+        .or_else(|| match mime_type.to_ascii_lowercase().as_str() {
+            "application/vnd.cups-postscript" => Some(DocumentFormat::PostScript),
+            "application/vnd.cups-pdf" => Some(DocumentFormat::Pdf),
+            _ => None,
+        })
+    }
+
+    /// Guess the format from the first bytes of the data, see [`SNIFF_LEN`].
+    /// A PJL header is skipped, its `ENTER LANGUAGE` command decides the format if present.
+    pub fn sniff(header: &[u8]) -> Option<DocumentFormat> {
+        match pjl_header(header) {
+            Some((Some(language), _)) => match language.as_str() {
+                "PCL" => Some(DocumentFormat::Pcl),
+                "POSTSCRIPT" => Some(DocumentFormat::PostScript),
+                "PDF" => Some(DocumentFormat::Pdf),
+                _ => None,
+            },
+            // DSC comments identify PostScript wrapped in PJL without a language
+            Some((None, data)) if contains(data, b"%!PS-Adobe-") => {
+                Some(DocumentFormat::PostScript)
+            }
+            Some((None, data)) => sniff_data(data),
+            None => sniff_data(header),
+        }
+    }
+}
+
+fn contains(data: &[u8], pattern: &[u8]) -> bool {
+    data.windows(pattern.len()).any(|w| w == pattern)
+}
+
+/// Split off a PJL header, returning the `ENTER LANGUAGE` value and the data after it.
+fn pjl_header(header: &[u8]) -> Option<(Option<String>, &[u8])> {
+    let mut rest = header.strip_prefix(UEL).unwrap_or(header);
+    if !rest.starts_with(b"@PJL") {
+        return if rest.len() < header.len() {
+            Some((None, rest))
+        } else {
+            None
+        };
+    }
+
+    let mut language = None;
+    while rest.starts_with(b"@PJL") {
+        let end = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|n| n + 1)
+            .unwrap_or(rest.len());
+        let command = String::from_utf8_lossy(&rest[..end])
+            .to_ascii_uppercase()
+            .split_whitespace()
+            .collect::<String>();
+        if let Some(value) = command.strip_prefix("@PJLENTERLANGUAGE=") {
+            language = Some(value.to_owned());
+        }
+        rest = &rest[end..];
+    }
+    Some((language, rest))
+}
+
+fn sniff_data(data: &[u8]) -> Option<DocumentFormat> {
+    if data.starts_with(b"%PDF-") {
+        Some(DocumentFormat::Pdf)
+    } else if data.starts_with(b"%!") || data.starts_with(b"\x04%!") {
+        Some(DocumentFormat::PostScript)
+    } else if data.starts_with(b"RaS2PwgRaster\0") {
+        Some(DocumentFormat::PwgRaster)
+    } else if data.starts_with(b"UNIRAST\0") {
+        Some(DocumentFormat::Urf)
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        Some(DocumentFormat::Jpeg)
+    } else if data.len() >= 2 && data[0] == 0x1b && b"E&*()".contains(&data[1]) {
+        Some(DocumentFormat::Pcl)
+    } else if is_text(data) {
+        Some(DocumentFormat::Text)
+    } else {
+        None
+    }
+}
+
+/// Returns true for UTF-8 without control characters other than whitespace.
+/// A multibyte sequence cut off at the end of the header is allowed.
+fn is_text(data: &[u8]) -> bool {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&data[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return false,
+    };
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r' | '\x0c'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic_signatures() {
+        let sniff = DocumentFormat::sniff;
+        assert_eq!(sniff(b"%PDF-1.7\n"), Some(DocumentFormat::Pdf));
+        assert_eq!(sniff(b"%!PS-Adobe-3.0\n"), Some(DocumentFormat::PostScript));
+        assert_eq!(sniff(b"\x04%!PS\n"), Some(DocumentFormat::PostScript));
+        assert_eq!(
+            sniff(b"RaS2PwgRaster\0\0\0"),
+            Some(DocumentFormat::PwgRaster)
+        );
+        assert_eq!(sniff(b"UNIRAST\0\0\0\0\x01"), Some(DocumentFormat::Urf));
+        assert_eq!(
+            sniff(b"\xff\xd8\xff\xe0\0\x10JFIF"),
+            Some(DocumentFormat::Jpeg)
+        );
+        assert_eq!(sniff(b"\x1bE\x1b&l26A"), Some(DocumentFormat::Pcl));
+        assert_eq!(sniff(b"Hello,\tworld\r\n\x0c"), Some(DocumentFormat::Text));
+        // a multibyte character cut off by the sniff length is still text
+        assert_eq!(sniff(&"héllo".as_bytes()[..2]), Some(DocumentFormat::Text));
+        assert_eq!(sniff(b"\x00\x01\x02binary"), None);
+        assert_eq!(sniff(b"\x1bZ"), None);
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(DocumentFormat::sniff(b""), None);
+        assert_eq!(DocumentFormat::sniff(UEL), None);
+    }
+
+    #[test]
+    fn pjl_enter_language() {
+        let header = b"\x1b%-12345X@PJL\r\n@PJL JOB NAME=\"x\"\r\n\
+                       @PJL ENTER LANGUAGE = PostScript\r\n%!PS\n";
+        assert_eq!(
+            DocumentFormat::sniff(header),
+            Some(DocumentFormat::PostScript)
+        );
+        let header = b"\x1b%-12345X@PJL ENTER LANGUAGE=PCL\r\n\x1bE";
+        assert_eq!(DocumentFormat::sniff(header), Some(DocumentFormat::Pcl));
+        let header = b"@PJL ENTER LANGUAGE=PDF\n%PDF-1.4\n";
+        assert_eq!(DocumentFormat::sniff(header), Some(DocumentFormat::Pdf));
+        // the declared language wins over the data
+        let header = b"\x1b%-12345X@PJL ENTER LANGUAGE=PCL\r\n%PDF-1.4\n";
+        assert_eq!(DocumentFormat::sniff(header), Some(DocumentFormat::Pcl));
+        let header = b"\x1b%-12345X@PJL ENTER LANGUAGE=ESCP\r\n";
+        assert_eq!(DocumentFormat::sniff(header), None);
+    }
+
+    #[test]
+    fn pjl_without_language() {
+        // DSC comments identify PostScript even if it does not start the data
+        let header = b"\x1b%-12345X@PJL JOB\r\n\r\n%%junk\n%!PS-Adobe-3.0\n";
+        assert_eq!(
+            DocumentFormat::sniff(header),
+            Some(DocumentFormat::PostScript)
+        );
+        let header = b"\x1b%-12345X@PJL JOB\r\n%PDF-1.4\n";
+        assert_eq!(DocumentFormat::sniff(header), Some(DocumentFormat::Pdf));
+        let header = b"\x1b%-12345X\x1bE";
+        assert_eq!(DocumentFormat::sniff(header), Some(DocumentFormat::Pcl));
+    }
+
+    #[test]
+    fn mime_types() {
+        assert_eq!(
+            DocumentFormat::from_mime_type("Text/Plain; charset=utf-8"),
+            Some(DocumentFormat::Text)
+        );
+        assert_eq!(
+            DocumentFormat::from_mime_type("application/vnd.cups-postscript"),
+            Some(DocumentFormat::PostScript)
+        );
+        assert_eq!(
+            DocumentFormat::from_mime_type("application/vnd.cups-raster"),
+            None
+        );
+    }
+}
@@ -0,0 +1,357 @@
+//! Minimal HTTP/1.1 client used by the network backends.
+//! Each request uses its own connection.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use log::debug;
+use native_tls::{TlsConnector, TlsStream};
+use url::Url;
+
+use crate::backends::connect_host;
+
+/// Largest response body accepted, responses are IPP messages and short status pages.
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Plain or TLS connection.
+pub(crate) enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.read(buf),
+            Stream::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.write(buf),
+            Stream::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) => s.flush(),
+            Stream::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// Request body with an optional length; chunked encoding is used if the length is unknown.
+pub struct Body<'a> {
+    pub content_type: &'a str,
+    pub reader: &'a mut dyn Read,
+    pub length: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Header value, the name is matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// Accept self-signed and otherwise invalid server certificates.
+    pub accept_invalid_certs: bool,
+    pub timeout: Duration,
+}
+
+impl Default for Client {
+    fn default() -> Client {
+        Client {
+            accept_invalid_certs: false,
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Start a TLS session on a connected socket.
+pub(crate) fn tls_connect(
+    host: &str,
+    stream: TcpStream,
+    accept_invalid_certs: bool,
+) -> io::Result<Stream> {
+    let connector = TlsConnector::builder()
+        .danger_accept_invalid_certs(accept_invalid_certs)
+        .danger_accept_invalid_hostnames(accept_invalid_certs)
+        .build()
+        .map_err(io::Error::other)?;
+    let stream = connector.connect(host, stream).map_err(io::Error::other)?;
+    Ok(Stream::Tls(Box::new(stream)))
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed by server",
+        ));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+fn write_chunked<W: Write>(out: &mut W, reader: &mut dyn Read) -> io::Result<()> {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        write!(out, "{:x}\r\n", n)?;
+        out.write_all(&buf[..n])?;
+        out.write_all(b"\r\n")?;
+    }
+    out.write_all(b"0\r\n\r\n")
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(invalid_data)?;
+        if size == 0 {
+            // trailers
+            while !read_line(reader)?.is_empty() {}
+            return Ok(body);
+        }
+        let start = body.len();
+        let end = start
+            .checked_add(size)
+            .filter(|&end| end <= MAX_BODY_SIZE)
+            .ok_or_else(|| invalid_data("response body too large"))?;
+        body.resize(end, 0);
+        reader.read_exact(&mut body[start..])?;
+        read_line(reader)?;
+    }
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client::default()
+    }
+
+    fn connect(&self, url: &Url) -> io::Result<Stream> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| invalid_data("no host in URL"))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| invalid_data("no port in URL"))?;
+
+        let stream = connect_host(host, port, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        match url.scheme() {
+            "http" => Ok(Stream::Plain(stream)),
+            "https" => tls_connect(host, stream, self.accept_invalid_certs),
+            scheme => Err(invalid_data(format!("unsupported scheme {}", scheme))),
+        }
+    }
+
+    /// Send a request and read the complete response.
+    pub fn send(
+        &self,
+        method: &str,
+        url: &Url,
+        headers: &[(&str, String)],
+        body: Option<Body>,
+    ) -> io::Result<Response> {
+        let mut stream = self.connect(url)?;
+
+        let mut target = url.path().to_owned();
+        if let Some(query) = url.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nUser-Agent: {}/{}\r\n",
+            method,
+            target,
+            host,
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        );
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if let Some(ref body) = body {
+            head.push_str(&format!("Content-Type: {}\r\n", body.content_type));
+            match body.length {
+                Some(length) => head.push_str(&format!("Content-Length: {}\r\n", length)),
+                None => head.push_str("Transfer-Encoding: chunked\r\n"),
+            }
+        }
+        head.push_str("\r\n");
+        debug!("{} {}", method, target);
+
+        let mut out = io::BufWriter::new(&mut stream);
+        out.write_all(head.as_bytes())?;
+        if let Some(body) = body {
+            match body.length {
+                Some(_) => {
+                    io::copy(body.reader, &mut out)?;
+                }
+                None => write_chunked(&mut out, body.reader)?,
+            }
+        }
+        out.flush()?;
+        drop(out);
+
+        let response = Client::read_response(&mut BufReader::new(&mut stream), method)?;
+        debug!("HTTP status {} {}", response.status, response.reason);
+        Ok(response)
+    }
+
+    fn read_response<R: BufRead>(reader: &mut R, method: &str) -> io::Result<Response> {
+        loop {
+            let status_line = read_line(reader)?;
+            let mut parts = status_line.splitn(3, ' ');
+            let version = parts.next().unwrap_or_default();
+            if !version.starts_with("HTTP/") {
+                return Err(invalid_data(format!("bad status line: {}", status_line)));
+            }
+            let status = parts
+                .next()
+                .and_then(|s| s.parse::<u16>().ok())
+                .ok_or_else(|| invalid_data(format!("bad status line: {}", status_line)))?;
+            let reason = parts.next().unwrap_or_default().to_owned();
+
+            let mut headers = Vec::new();
+            loop {
+                let line = read_line(reader)?;
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    headers.push((name.trim().to_owned(), value.trim().to_owned()));
+                }
+            }
+
+            // skip interim responses such as 100 Continue
+            if (100..200).contains(&status) {
+                continue;
+            }
+
+            let mut response = Response {
+                status,
+                reason,
+                headers,
+                body: Vec::new(),
+            };
+            if method == "HEAD" || status == 204 || status == 304 {
+                return Ok(response);
+            }
+
+            let chunked = response
+                .header("Transfer-Encoding")
+                .map(|te| te.to_ascii_lowercase().contains("chunked"))
+                .unwrap_or(false);
+            if chunked {
+                response.body = read_chunked(reader)?;
+            } else if let Some(length) = response.header("Content-Length") {
+                let length = length.parse::<u64>().map_err(invalid_data)?;
+                if length > MAX_BODY_SIZE as u64 {
+                    return Err(invalid_data("response body too large"));
+                }
+                reader.take(length).read_to_end(&mut response.body)?;
+                if (response.body.len() as u64) < length {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "truncated response body",
+                    ));
+                }
+            } else {
+                reader
+                    .take(MAX_BODY_SIZE as u64 + 1)
+                    .read_to_end(&mut response.body)?;
+                if response.body.len() > MAX_BODY_SIZE {
+                    return Err(invalid_data("response body too large"));
+                }
+            }
+            return Ok(response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(response: &[u8]) -> io::Result<Response> {
+        Client::read_response(&mut io::Cursor::new(response), "POST")
+    }
+
+    #[test]
+    fn chunked_body() {
+        let response = read(
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;ext=1\r\nhello\r\n1\r\n!\r\n0\r\nTrailer: x\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello!");
+    }
+
+    #[test]
+    fn oversized_chunks() {
+        for size in ["ffffffffffffffff", "1000001", "zz"] {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\n{}\r\n",
+                size
+            );
+            let error = read(response.as_bytes()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn content_length() {
+        let response = read(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabcdef").unwrap();
+        assert_eq!(response.body, b"abc");
+
+        let error = read(b"HTTP/1.1 200 OK\r\nContent-Length: 99999999999\r\n\r\n").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let error = read(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nabc").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
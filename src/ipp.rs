@@ -0,0 +1,922 @@
+//! IPP message encoding and decoding (RFC 8010).
+
+use std::{error, fmt};
+
+/// Delimiter tags starting attribute groups.
+pub mod group {
+    pub const OPERATION: u8 = 0x01;
+    pub const JOB: u8 = 0x02;
+    pub const END: u8 = 0x03;
+    pub const PRINTER: u8 = 0x04;
+    pub const UNSUPPORTED: u8 = 0x05;
+    pub const SUBSCRIPTION: u8 = 0x06;
+    pub const EVENT_NOTIFICATION: u8 = 0x07;
+    pub const RESOURCE: u8 = 0x08;
+    pub const DOCUMENT: u8 = 0x09;
+    pub const SYSTEM: u8 = 0x0a;
+}
+
+/// Value tags.
+pub mod tag {
+    pub const UNSUPPORTED: u8 = 0x10;
+    pub const DEFAULT: u8 = 0x11;
+    pub const UNKNOWN: u8 = 0x12;
+    pub const NO_VALUE: u8 = 0x13;
+    pub const NOT_SETTABLE: u8 = 0x15;
+    pub const DELETE_ATTRIBUTE: u8 = 0x16;
+    pub const ADMIN_DEFINE: u8 = 0x17;
+    pub const INTEGER: u8 = 0x21;
+    pub const BOOLEAN: u8 = 0x22;
+    pub const ENUM: u8 = 0x23;
+    pub const OCTET_STRING: u8 = 0x30;
+    pub const DATE_TIME: u8 = 0x31;
+    pub const RESOLUTION: u8 = 0x32;
+    pub const RANGE_OF_INTEGER: u8 = 0x33;
+    pub const BEG_COLLECTION: u8 = 0x34;
+    pub const TEXT_WITH_LANGUAGE: u8 = 0x35;
+    pub const NAME_WITH_LANGUAGE: u8 = 0x36;
+    pub const END_COLLECTION: u8 = 0x37;
+    pub const TEXT: u8 = 0x41;
+    pub const NAME: u8 = 0x42;
+    pub const KEYWORD: u8 = 0x44;
+    pub const URI: u8 = 0x45;
+    pub const URI_SCHEME: u8 = 0x46;
+    pub const CHARSET: u8 = 0x47;
+    pub const NATURAL_LANGUAGE: u8 = 0x48;
+    pub const MIME_MEDIA_TYPE: u8 = 0x49;
+    pub const MEMBER_ATTR_NAME: u8 = 0x4a;
+    pub const EXTENSION: u8 = 0x7f;
+
+    /// Group delimiter tags occupy the range below the value tags.
+    pub fn is_delimiter(tag: u8) -> bool {
+        tag < 0x10
+    }
+
+    /// Out-of-band values carry no data, only the tag.
+    pub fn is_out_of_band(tag: u8) -> bool {
+        (0x10..0x20).contains(&tag)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    PrintJob = 0x0002,
+    ValidateJob = 0x0004,
+    CreateJob = 0x0005,
+    SendDocument = 0x0006,
+    CancelJob = 0x0008,
+    GetJobAttributes = 0x0009,
+    GetPrinterAttributes = 0x000b,
+}
+
+/// Status codes used by the backends.
+pub mod status {
+    pub const SUCCESSFUL_OK: u16 = 0x0000;
+    pub const CLIENT_ERROR_BAD_REQUEST: u16 = 0x0400;
+    pub const CLIENT_ERROR_FORBIDDEN: u16 = 0x0401;
+    pub const CLIENT_ERROR_NOT_AUTHENTICATED: u16 = 0x0402;
+    pub const CLIENT_ERROR_NOT_AUTHORIZED: u16 = 0x0403;
+    pub const CLIENT_ERROR_NOT_POSSIBLE: u16 = 0x0404;
+    pub const CLIENT_ERROR_TIMEOUT: u16 = 0x0405;
+    pub const CLIENT_ERROR_NOT_FOUND: u16 = 0x0406;
+    pub const CLIENT_ERROR_DOCUMENT_FORMAT_NOT_SUPPORTED: u16 = 0x040a;
+    pub const CLIENT_ERROR_ATTRIBUTES_OR_VALUES_NOT_SUPPORTED: u16 = 0x040b;
+    pub const SERVER_ERROR_INTERNAL_ERROR: u16 = 0x0500;
+    pub const SERVER_ERROR_OPERATION_NOT_SUPPORTED: u16 = 0x0501;
+    pub const SERVER_ERROR_SERVICE_UNAVAILABLE: u16 = 0x0502;
+    pub const SERVER_ERROR_TEMPORARY_ERROR: u16 = 0x0505;
+    pub const SERVER_ERROR_NOT_ACCEPTING_JOBS: u16 = 0x0506;
+    pub const SERVER_ERROR_BUSY: u16 = 0x0507;
+
+    pub fn is_successful(status: u16) -> bool {
+        status < 0x0100
+    }
+}
+
+/// Attribute value. String values hold UTF-8 text as sent on the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Integer(i32),
+    Boolean(bool),
+    Enum(i32),
+    OctetString(Vec<u8>),
+    DateTime([u8; 11]),
+    Resolution {
+        cross_feed: i32,
+        feed: i32,
+        units: u8,
+    },
+    RangeOfInteger(i32, i32),
+    TextWithLanguage {
+        language: String,
+        text: String,
+    },
+    NameWithLanguage {
+        language: String,
+        name: String,
+    },
+    Text(String),
+    Name(String),
+    Keyword(String),
+    Uri(String),
+    UriScheme(String),
+    Charset(String),
+    NaturalLanguage(String),
+    MimeMediaType(String),
+    /// Collection members, each with one or more values.
+    Collection(Vec<Attribute>),
+    /// Out-of-band value such as `unknown` or `no-value`.
+    OutOfBand(u8),
+    /// Any value with a tag not listed above, kept as raw bytes.
+    Other {
+        tag: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl Value {
+    pub fn tag(&self) -> u8 {
+        match *self {
+            Value::Integer(_) => tag::INTEGER,
+            Value::Boolean(_) => tag::BOOLEAN,
+            Value::Enum(_) => tag::ENUM,
+            Value::OctetString(_) => tag::OCTET_STRING,
+            Value::DateTime(_) => tag::DATE_TIME,
+            Value::Resolution { .. } => tag::RESOLUTION,
+            Value::RangeOfInteger(..) => tag::RANGE_OF_INTEGER,
+            Value::TextWithLanguage { .. } => tag::TEXT_WITH_LANGUAGE,
+            Value::NameWithLanguage { .. } => tag::NAME_WITH_LANGUAGE,
+            Value::Text(_) => tag::TEXT,
+            Value::Name(_) => tag::NAME,
+            Value::Keyword(_) => tag::KEYWORD,
+            Value::Uri(_) => tag::URI,
+            Value::UriScheme(_) => tag::URI_SCHEME,
+            Value::Charset(_) => tag::CHARSET,
+            Value::NaturalLanguage(_) => tag::NATURAL_LANGUAGE,
+            Value::MimeMediaType(_) => tag::MIME_MEDIA_TYPE,
+            Value::Collection(_) => tag::BEG_COLLECTION,
+            Value::OutOfBand(tag) => tag,
+            Value::Other { tag, .. } => tag,
+        }
+    }
+
+    /// Integer or enum value.
+    pub fn as_i32(&self) -> Option<i32> {
+        match *self {
+            Value::Integer(v) | Value::Enum(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Value of any of the string types.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::TextWithLanguage { ref text, .. } => Some(text),
+            Value::NameWithLanguage { ref name, .. } => Some(name),
+            Value::Text(ref s)
+            | Value::Name(ref s)
+            | Value::Keyword(ref s)
+            | Value::Uri(ref s)
+            | Value::UriScheme(ref s)
+            | Value::Charset(ref s)
+            | Value::NaturalLanguage(ref s)
+            | Value::MimeMediaType(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_collection(&self) -> Option<&[Attribute]> {
+        match *self {
+            Value::Collection(ref members) => Some(members),
+            _ => None,
+        }
+    }
+
+    pub fn is_out_of_band(&self) -> bool {
+        matches!(*self, Value::OutOfBand(_))
+    }
+
+    /// Write the value length and data, plus the members of a collection.
+    /// Data longer than a length field allows is truncated, strings at a character boundary.
+    fn encode_data(&self, buf: &mut Vec<u8>) {
+        fn with_language(buf: &mut Vec<u8>, language: &str, value: &str) {
+            let language = truncate(language, MAX_FIELD_LEN - 4);
+            let value = truncate(value, MAX_FIELD_LEN - 4 - language.len());
+            let len = 4 + language.len() + value.len();
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+            bytes(buf, language.as_bytes());
+            bytes(buf, value.as_bytes());
+        }
+        fn bytes(buf: &mut Vec<u8>, data: &[u8]) {
+            let data = &data[..data.len().min(MAX_FIELD_LEN)];
+            buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            buf.extend_from_slice(data);
+        }
+
+        match *self {
+            Value::Integer(v) | Value::Enum(v) => bytes(buf, &v.to_be_bytes()),
+            Value::Boolean(v) => bytes(buf, &[v as u8]),
+            Value::OctetString(ref data) | Value::Other { ref data, .. } => bytes(buf, data),
+            Value::OutOfBand(_) => bytes(buf, &[]),
+            Value::Collection(ref members) => {
+                bytes(buf, &[]);
+                for member in members {
+                    buf.push(tag::MEMBER_ATTR_NAME);
+                    bytes(buf, &[]);
+                    bytes(buf, truncate(&member.name, MAX_FIELD_LEN).as_bytes());
+                    for value in &member.values {
+                        buf.push(value.tag());
+                        bytes(buf, &[]);
+                        value.encode_data(buf);
+                    }
+                }
+                buf.push(tag::END_COLLECTION);
+                bytes(buf, &[]);
+                bytes(buf, &[]);
+            }
+            Value::DateTime(ref data) => bytes(buf, data),
+            Value::Resolution {
+                cross_feed,
+                feed,
+                units,
+            } => {
+                let mut data = Vec::with_capacity(9);
+                data.extend_from_slice(&cross_feed.to_be_bytes());
+                data.extend_from_slice(&feed.to_be_bytes());
+                data.push(units);
+                bytes(buf, &data);
+            }
+            Value::RangeOfInteger(lower, upper) => {
+                let mut data = Vec::with_capacity(8);
+                data.extend_from_slice(&lower.to_be_bytes());
+                data.extend_from_slice(&upper.to_be_bytes());
+                bytes(buf, &data);
+            }
+            Value::TextWithLanguage {
+                ref language,
+                ref text,
+            } => with_language(buf, language, text),
+            Value::NameWithLanguage {
+                ref language,
+                ref name,
+            } => with_language(buf, language, name),
+            Value::Text(ref s)
+            | Value::Name(ref s)
+            | Value::Keyword(ref s)
+            | Value::Uri(ref s)
+            | Value::UriScheme(ref s)
+            | Value::Charset(ref s)
+            | Value::NaturalLanguage(ref s)
+            | Value::MimeMediaType(ref s) => bytes(buf, truncate(s, MAX_FIELD_LEN).as_bytes()),
+        }
+    }
+
+    /// Decode a value other than a collection. In lenient mode values with
+    /// a malformed length are kept as [`Value::Other`].
+    fn decode(tag: u8, data: &[u8], mode: ParseMode) -> Result<Value, IppError> {
+        match Value::decode_checked(tag, data, mode) {
+            Err(IppError::InvalidValue(_)) if mode == ParseMode::Lenient => Ok(Value::Other {
+                tag,
+                data: data.to_vec(),
+            }),
+            result => result,
+        }
+    }
+
+    fn decode_checked(tag: u8, data: &[u8], mode: ParseMode) -> Result<Value, IppError> {
+        let string = || decode_string(tag, data, mode);
+        let int = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|b| i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or(IppError::InvalidValue(tag))
+        };
+        let fixed = |len: usize| {
+            if data.len() == len {
+                Ok(())
+            } else {
+                Err(IppError::InvalidValue(tag))
+            }
+        };
+
+        let value = match tag {
+            _ if tag::is_out_of_band(tag) => {
+                if mode == ParseMode::Strict {
+                    fixed(0)?;
+                }
+                Value::OutOfBand(tag)
+            }
+            tag::INTEGER => {
+                fixed(4)?;
+                Value::Integer(int(0)?)
+            }
+            tag::ENUM => {
+                fixed(4)?;
+                Value::Enum(int(0)?)
+            }
+            tag::BOOLEAN => {
+                fixed(1)?;
+                if mode == ParseMode::Strict && data[0] > 1 {
+                    return Err(IppError::InvalidValue(tag));
+                }
+                Value::Boolean(data[0] != 0)
+            }
+            tag::OCTET_STRING => Value::OctetString(data.to_vec()),
+            tag::DATE_TIME => {
+                fixed(11)?;
+                let mut date = [0u8; 11];
+                date.copy_from_slice(data);
+                Value::DateTime(date)
+            }
+            tag::RESOLUTION => {
+                fixed(9)?;
+                Value::Resolution {
+                    cross_feed: int(0)?,
+                    feed: int(4)?,
+                    units: data[8],
+                }
+            }
+            tag::RANGE_OF_INTEGER => {
+                fixed(8)?;
+                Value::RangeOfInteger(int(0)?, int(4)?)
+            }
+            tag::TEXT_WITH_LANGUAGE | tag::NAME_WITH_LANGUAGE => {
+                let mut reader = Reader::new(data);
+                let field = |reader: &mut Reader| {
+                    reader
+                        .field()
+                        .map_err(|_| IppError::InvalidValue(tag))
+                        .and_then(|f| decode_string(tag, f, mode))
+                };
+                let language = field(&mut reader)?;
+                let value = field(&mut reader)?;
+                if !reader.is_empty() {
+                    return Err(IppError::InvalidValue(tag));
+                }
+                if tag == tag::TEXT_WITH_LANGUAGE {
+                    Value::TextWithLanguage {
+                        language,
+                        text: value,
+                    }
+                } else {
+                    Value::NameWithLanguage {
+                        language,
+                        name: value,
+                    }
+                }
+            }
+            tag::TEXT => Value::Text(string()?),
+            tag::NAME => Value::Name(string()?),
+            tag::KEYWORD => Value::Keyword(string()?),
+            tag::URI => Value::Uri(string()?),
+            tag::URI_SCHEME => Value::UriScheme(string()?),
+            tag::CHARSET => Value::Charset(string()?),
+            tag::NATURAL_LANGUAGE => Value::NaturalLanguage(string()?),
+            tag::MIME_MEDIA_TYPE => Value::MimeMediaType(string()?),
+            tag::END_COLLECTION | tag::MEMBER_ATTR_NAME => return Err(IppError::BadCollection),
+            _ => Value::Other {
+                tag,
+                data: data.to_vec(),
+            },
+        };
+        Ok(value)
+    }
+}
+
+/// Longest string at most `max_len` bytes long that does not split a character.
+fn truncate(value: &str, max_len: usize) -> &str {
+    if value.len() <= max_len {
+        return value;
+    }
+    let end = (0..=max_len)
+        .rev()
+        .find(|&end| value.is_char_boundary(end))
+        .unwrap_or_default();
+    &value[..end]
+}
+
+/// Strict decoding requires valid UTF-8, lenient decoding replaces invalid sequences.
+fn decode_string(tag: u8, data: &[u8], mode: ParseMode) -> Result<String, IppError> {
+    match mode {
+        ParseMode::Strict => {
+            String::from_utf8(data.to_vec()).map_err(|_| IppError::InvalidValue(tag))
+        }
+        ParseMode::Lenient => Ok(String::from_utf8_lossy(data).into_owned()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attribute {
+    pub name: String,
+    pub values: Vec<Value>,
+}
+
+impl Attribute {
+    pub fn new<S: Into<String>>(name: S, value: Value) -> Attribute {
+        Attribute {
+            name: name.into(),
+            values: vec![value],
+        }
+    }
+
+    pub fn with_values<S: Into<String>>(name: S, values: Vec<Value>) -> Attribute {
+        Attribute {
+            name: name.into(),
+            values,
+        }
+    }
+
+    pub fn value(&self) -> Option<&Value> {
+        self.values.first()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttributeGroup {
+    pub tag: u8,
+    pub attributes: Vec<Attribute>,
+}
+
+/// IPP request or response. `code` is the operation ID for requests and
+/// the status code for responses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IppMessage {
+    pub version: (u8, u8),
+    pub code: u16,
+    pub request_id: u32,
+    pub groups: Vec<AttributeGroup>,
+}
+
+/// How forgiving [`IppMessage::decode_with`] is about malformed input.
+/// Structural errors such as truncation are always reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Reject values with a wrong length or invalid UTF-8, out-of-band values
+    /// with data, reserved group tags and unsupported versions.
+    Strict,
+    /// Keep malformed values as [`Value::Other`] and decode strings lossily.
+    Lenient,
+}
+
+/// Longest name or value, the length fields are 16 bits.
+const MAX_FIELD_LEN: usize = u16::MAX as usize;
+
+/// Limit for nested collections.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IppError {
+    /// The message ended in the middle of a field.
+    Truncated,
+    /// The message has no end-of-attributes tag.
+    MissingEnd,
+    /// A value appeared before the first attribute group.
+    UnexpectedValue,
+    /// A value with the given tag has invalid contents.
+    InvalidValue(u8),
+    /// Reserved delimiter tag.
+    InvalidTag(u8),
+    /// Unsupported major version.
+    InvalidVersion(u8, u8),
+    /// Collection members out of order or an unterminated collection.
+    BadCollection,
+    /// Collections are nested deeper than the supported limit.
+    NestingTooDeep,
+}
+
+impl fmt::Display for IppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            IppError::Truncated => write!(f, "truncated IPP message"),
+            IppError::MissingEnd => write!(f, "missing end-of-attributes tag"),
+            IppError::UnexpectedValue => write!(f, "attribute outside of a group"),
+            IppError::InvalidValue(tag) => write!(f, "invalid value for tag 0x{:02x}", tag),
+            IppError::InvalidTag(tag) => write!(f, "reserved delimiter tag 0x{:02x}", tag),
+            IppError::InvalidVersion(major, minor) => {
+                write!(f, "unsupported IPP version {}.{}", major, minor)
+            }
+            IppError::BadCollection => write!(f, "malformed collection"),
+            IppError::NestingTooDeep => write!(f, "collections nested too deeply"),
+        }
+    }
+}
+
+impl error::Error for IppError {}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], IppError> {
+        let end = self.pos.checked_add(len).ok_or(IppError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(IppError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, IppError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, IppError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, IppError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Length-prefixed byte string.
+    fn field(&mut self) -> Result<&'a [u8], IppError> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    /// Tag, name and value data of the next attribute value.
+    fn value(&mut self) -> Result<(u8, &'a [u8], &'a [u8]), IppError> {
+        let tag = self.u8()?;
+        let name = self.field()?;
+        let data = self.field()?;
+        Ok((tag, name, data))
+    }
+
+    /// Collection members following a begCollection value, up to the matching endCollection.
+    fn collection(&mut self, mode: ParseMode, depth: usize) -> Result<Value, IppError> {
+        if depth > MAX_DEPTH {
+            return Err(IppError::NestingTooDeep);
+        }
+        let mut members: Vec<Attribute> = Vec::new();
+        loop {
+            let (tag, name, data) = self.value()?;
+            if mode == ParseMode::Strict && !name.is_empty() {
+                return Err(IppError::BadCollection);
+            }
+            match tag {
+                tag::END_COLLECTION => {
+                    if mode == ParseMode::Strict
+                        && (!data.is_empty() || members.iter().any(|m| m.values.is_empty()))
+                    {
+                        return Err(IppError::BadCollection);
+                    }
+                    return Ok(Value::Collection(members));
+                }
+                tag::MEMBER_ATTR_NAME => {
+                    if mode == ParseMode::Strict && data.is_empty() {
+                        return Err(IppError::BadCollection);
+                    }
+                    members.push(Attribute::with_values(
+                        decode_string(tag, data, mode)?,
+                        Vec::new(),
+                    ));
+                }
+                _ if tag::is_delimiter(tag) => return Err(IppError::BadCollection),
+                _ => {
+                    let value = self.attribute_value(tag, data, mode, depth)?;
+                    members
+                        .last_mut()
+                        .ok_or(IppError::BadCollection)?
+                        .values
+                        .push(value);
+                }
+            }
+        }
+    }
+
+    fn attribute_value(
+        &mut self,
+        tag: u8,
+        data: &[u8],
+        mode: ParseMode,
+        depth: usize,
+    ) -> Result<Value, IppError> {
+        if tag == tag::BEG_COLLECTION {
+            if mode == ParseMode::Strict && !data.is_empty() {
+                return Err(IppError::InvalidValue(tag));
+            }
+            self.collection(mode, depth + 1)
+        } else {
+            Value::decode(tag, data, mode)
+        }
+    }
+}
+
+impl IppMessage {
+    /// New IPP/2.0 request with the mandatory charset and language attributes.
+    pub fn request(operation: Operation, request_id: u32) -> IppMessage {
+        let mut message = IppMessage {
+            version: (2, 0),
+            code: operation as u16,
+            request_id,
+            groups: Vec::new(),
+        };
+        message.add(
+            group::OPERATION,
+            Attribute::new("attributes-charset", Value::Charset(String::from("utf-8"))),
+        );
+        message.add(
+            group::OPERATION,
+            Attribute::new(
+                "attributes-natural-language",
+                Value::NaturalLanguage(String::from("en")),
+            ),
+        );
+        message
+    }
+
+    /// Append an attribute to the last group with the given tag, creating the group if needed.
+    pub fn add(&mut self, group_tag: u8, attribute: Attribute) {
+        match self.groups.iter_mut().rev().find(|g| g.tag == group_tag) {
+            Some(group) => group.attributes.push(attribute),
+            None => self.groups.push(AttributeGroup {
+                tag: group_tag,
+                attributes: vec![attribute],
+            }),
+        }
+    }
+
+    /// First attribute with the given name in any group.
+    pub fn attribute(&self, name: &str) -> Option<&Attribute> {
+        self.groups
+            .iter()
+            .flat_map(|g| g.attributes.iter())
+            .find(|a| a.name == name)
+    }
+
+    /// First attribute with the given name in groups with the given tag.
+    pub fn group_attribute(&self, group_tag: u8, name: &str) -> Option<&Attribute> {
+        self.groups
+            .iter()
+            .filter(|g| g.tag == group_tag)
+            .flat_map(|g| g.attributes.iter())
+            .find(|a| a.name == name)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.push(self.version.0);
+        buf.push(self.version.1);
+        buf.extend_from_slice(&self.code.to_be_bytes());
+        buf.extend_from_slice(&self.request_id.to_be_bytes());
+
+        for group in &self.groups {
+            buf.push(group.tag);
+            for attribute in &group.attributes {
+                for (i, value) in attribute.values.iter().enumerate() {
+                    buf.push(value.tag());
+                    // additional values of a 1setOf attribute have an empty name
+                    let name = if i == 0 {
+                        truncate(&attribute.name, MAX_FIELD_LEN).as_bytes()
+                    } else {
+                        b""
+                    };
+                    buf.extend_from_slice(&(name.len() as u16).to_be_bytes());
+                    buf.extend_from_slice(name);
+                    value.encode_data(&mut buf);
+                }
+            }
+        }
+        buf.push(group::END);
+        buf
+    }
+
+    /// Decode a message leniently, returning it together with the offset of the
+    /// document data following the attributes.
+    pub fn decode(data: &[u8]) -> Result<(IppMessage, usize), IppError> {
+        IppMessage::decode_with(data, ParseMode::Lenient)
+    }
+
+    /// Decode a message with the given strictness. Malformed input is reported
+    /// as an error, never as a panic.
+    pub fn decode_with(data: &[u8], mode: ParseMode) -> Result<(IppMessage, usize), IppError> {
+        let mut reader = Reader::new(data);
+        let version = (reader.u8()?, reader.u8()?);
+        if mode == ParseMode::Strict && !(1..=2).contains(&version.0) {
+            return Err(IppError::InvalidVersion(version.0, version.1));
+        }
+        let code = reader.u16()?;
+        let request_id = reader.u32()?;
+
+        let mut groups: Vec<AttributeGroup> = Vec::new();
+        loop {
+            let tag = match reader.data.get(reader.pos) {
+                Some(&tag) => tag,
+                None => return Err(IppError::MissingEnd),
+            };
+            if tag == group::END {
+                reader.pos += 1;
+                break;
+            }
+            if tag::is_delimiter(tag) {
+                reader.pos += 1;
+                if mode == ParseMode::Strict && (tag == 0 || tag > group::SYSTEM) {
+                    return Err(IppError::InvalidTag(tag));
+                }
+                groups.push(AttributeGroup {
+                    tag,
+                    attributes: Vec::new(),
+                });
+                continue;
+            }
+
+            let (tag, name, data) = reader.value()?;
+            let name = decode_string(tag, name, mode)?;
+            let value = reader.attribute_value(tag, data, mode, 0)?;
+            let group = groups.last_mut().ok_or(IppError::UnexpectedValue)?;
+            match group.attributes.last_mut() {
+                Some(attribute) if name.is_empty() => attribute.values.push(value),
+                None if name.is_empty() => return Err(IppError::UnexpectedValue),
+                _ => group.attributes.push(Attribute::new(name, value)),
+            }
+        }
+
+        Ok((
+            IppMessage {
+                version,
+                code,
+                request_id,
+                groups,
+            },
+            reader.pos,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> IppMessage {
+        let mut message = IppMessage::request(Operation::PrintJob, 7);
+        message.add(
+            group::OPERATION,
+            Attribute::new(
+                "job-name",
+                Value::NameWithLanguage {
+                    language: String::from("de"),
+                    name: String::from("Bericht für März"),
+                },
+            ),
+        );
+        let media_size = Value::Collection(vec![
+            Attribute::new("x-dimension", Value::Integer(21000)),
+            Attribute::new("y-dimension", Value::Integer(29700)),
+        ]);
+        message.add(
+            group::JOB,
+            Attribute::new(
+                "media-col",
+                Value::Collection(vec![
+                    Attribute::new("media-size", media_size),
+                    Attribute::with_values(
+                        "media-source",
+                        vec![
+                            Value::Keyword(String::from("tray-1")),
+                            Value::Keyword(String::from("tray-2")),
+                        ],
+                    ),
+                ]),
+            ),
+        );
+        message.add(
+            group::JOB,
+            Attribute::with_values(
+                "page-ranges",
+                vec![Value::RangeOfInteger(1, 3), Value::RangeOfInteger(7, 9)],
+            ),
+        );
+        message.add(
+            group::JOB,
+            Attribute::new(
+                "printer-resolution",
+                Value::Resolution {
+                    cross_feed: 600,
+                    feed: 600,
+                    units: 3,
+                },
+            ),
+        );
+        message.add(
+            group::JOB,
+            Attribute::new("sides", Value::OutOfBand(tag::DEFAULT)),
+        );
+        message.add(
+            group::JOB,
+            Attribute::new("job-hold-until", Value::OutOfBand(tag::NO_VALUE)),
+        );
+        message
+    }
+
+    #[test]
+    fn round_trip() {
+        let message = sample();
+        let encoded = message.encode();
+        for mode in [ParseMode::Strict, ParseMode::Lenient] {
+            let (decoded, offset) = IppMessage::decode_with(&encoded, mode).unwrap();
+            assert_eq!(decoded, message);
+            assert_eq!(offset, encoded.len());
+        }
+
+        let col = message
+            .attribute("media-col")
+            .and_then(Attribute::value)
+            .unwrap();
+        let members = col.as_collection().unwrap();
+        assert_eq!(members[1].values.len(), 2);
+        assert!(message.attribute("sides").unwrap().values[0].is_out_of_band());
+    }
+
+    #[test]
+    fn document_offset() {
+        let mut encoded = sample().encode();
+        let len = encoded.len();
+        encoded.extend_from_slice(b"%PDF-1.4");
+        let (_, offset) = IppMessage::decode(&encoded).unwrap();
+        assert_eq!(offset, len);
+    }
+
+    #[test]
+    fn long_values_are_truncated() {
+        let long = "ä".repeat(40000);
+        let mut message = IppMessage::request(Operation::PrintJob, 1);
+        message.add(
+            group::OPERATION,
+            Attribute::new(
+                "job-name",
+                Value::NameWithLanguage {
+                    language: String::from("en"),
+                    name: long.clone(),
+                },
+            ),
+        );
+        message.add(group::JOB, Attribute::new("job-info", Value::Text(long)));
+
+        let (decoded, _) = IppMessage::decode_with(&message.encode(), ParseMode::Strict).unwrap();
+        match decoded.attribute("job-name").and_then(Attribute::value) {
+            Some(Value::NameWithLanguage { language, name }) => {
+                assert_eq!(language, "en");
+                assert_eq!(4 + language.len() + name.len(), MAX_FIELD_LEN - 1);
+            }
+            value => panic!("unexpected value {:?}", value),
+        }
+        let text = decoded.attribute("job-info").and_then(Attribute::value);
+        assert_eq!(
+            text.and_then(Value::as_str).unwrap().len(),
+            MAX_FIELD_LEN - 1
+        );
+    }
+
+    #[test]
+    fn malformed_input_does_not_panic() {
+        let encoded = sample().encode();
+        let decode = |data: &[u8]| {
+            let _ = IppMessage::decode_with(data, ParseMode::Strict);
+            let _ = IppMessage::decode_with(data, ParseMode::Lenient);
+        };
+
+        for len in 0..encoded.len() {
+            decode(&encoded[..len]);
+        }
+        for pos in 0..encoded.len() {
+            for byte in [0x00, 0x01, 0x03, 0x13, 0x34, 0x37, 0x4a, 0x7f, 0xff] {
+                let mut data = encoded.clone();
+                data[pos] = byte;
+                decode(&data);
+            }
+        }
+
+        // pseudo-random input, with a valid header to get past the first checks
+        let mut seed = 0x2545_f491_u32;
+        for _ in 0..2000 {
+            let mut data = vec![2, 0, 0, 2, 0, 0, 0, 1, group::OPERATION];
+            for _ in 0..(seed % 64) {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                data.push(seed as u8);
+            }
+            decode(&data);
+        }
+
+        // deeply nested collections
+        let mut data = vec![2, 0, 0, 2, 0, 0, 0, 1, group::JOB];
+        for _ in 0..1000 {
+            data.extend_from_slice(&[tag::BEG_COLLECTION, 0, 1, b'c', 0, 0]);
+            data.extend_from_slice(&[tag::MEMBER_ATTR_NAME, 0, 0, 0, 1, b'm']);
+        }
+        assert_eq!(
+            IppMessage::decode(&data).unwrap_err(),
+            IppError::NestingTooDeep
+        );
+    }
+}
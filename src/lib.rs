@@ -0,0 +1,17 @@
+pub mod auth;
+pub mod backchannel;
+pub mod backends;
+pub mod cupsbackend;
+pub mod device;
+pub mod environment;
+mod fd;
+pub mod format;
+pub mod http;
+pub mod ipp;
+pub mod options;
+pub mod pjl;
+pub mod sidechannel;
+pub mod status;
+pub mod uri;
+
+pub use crate::cupsbackend::*;
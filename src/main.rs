@@ -1,5 +1,23 @@
-pub mod cupsbackend;
+use cupsbackend::{Backend, BackendData, CupsBackend, ExitCode, Result};
+use log::info;
+
+struct TestBackend;
+
+impl Backend for TestBackend {
+    fn name(&self) -> &str {
+        "testbackend"
+    }
+
+    fn description(&self) -> &str {
+        "CUPS backend in Rust"
+    }
+
+    fn submit_job(&mut self, data: &BackendData) -> Result<ExitCode> {
+        info!("Sending {} to {}", data.title, data.printer_uri);
+        Ok(ExitCode::Success)
+    }
+}
 
 fn main() {
-    cupsbackend::CupsBackend::new().run();
+    CupsBackend::new(TestBackend).run();
 }
@@ -0,0 +1,744 @@
+//! CUPS option strings as passed in the fifth backend argument, and typed
+//! accessors for well-known job options.
+
+use std::{fmt, ops::RangeInclusive, result, str::FromStr};
+
+use crate::{BackendData, BackendError, Result};
+
+fn is_space(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0b' | '\x0c')
+}
+
+fn add_option(options: &mut Vec<(String, String)>, name: &str, value: String) {
+    match options
+        .iter_mut()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+    {
+        Some(option) => option.1 = value,
+        None => options.push((name.to_owned(), value)),
+    }
+}
+
+/// Parse an option string the same way as `cupsParseOptions`:
+///
+/// * `name=value` pairs separated by whitespace;
+/// * values may be quoted with `'` or `"` and contain backslash escapes;
+/// * `{...}` collection values are kept verbatim including the braces;
+/// * a bare `name` means `name=true` and `noname` means `name=false`;
+/// * options with an empty name are dropped;
+/// * a later option replaces an earlier one with the same (case-insensitive) name.
+///
+/// Option order is preserved.
+pub fn parse_options(arg: &str) -> Vec<(String, String)> {
+    let mut chars: Vec<char> = arg.chars().collect();
+    if chars.len() >= 2 && chars[0] == '{' && chars[chars.len() - 1] == '}' {
+        chars = chars[1..chars.len() - 1].to_vec();
+    }
+    let c = &chars;
+    let len = c.len();
+    let mut options = Vec::new();
+
+    let mut i = 0;
+    while i < len && is_space(c[i]) {
+        i += 1;
+    }
+
+    while i < len {
+        let start = i;
+        while i < len && !is_space(c[i]) && c[i] != '=' {
+            i += 1;
+        }
+        let name: String = c[start..i].iter().collect();
+
+        while i < len && is_space(c[i]) {
+            i += 1;
+        }
+
+        if i >= len || c[i] != '=' {
+            let (name, value) = match name
+                .get(..2)
+                .filter(|prefix| prefix.eq_ignore_ascii_case("no"))
+            {
+                Some(prefix) => (&name[prefix.len()..], "false"),
+                None => (name.as_str(), "true"),
+            };
+            if !name.is_empty() {
+                add_option(&mut options, name, value.to_owned());
+            }
+            continue;
+        }
+        i += 1;
+
+        // one character, quoted string or collection per iteration, so that
+        // quotes and braces may follow plain text within the same value
+        let mut value = String::new();
+        while i < len && !is_space(c[i]) {
+            match c[i] {
+                quote @ ('\'' | '"') => {
+                    i += 1;
+                    while i < len && c[i] != quote {
+                        if c[i] == '\\' && i + 1 < len {
+                            i += 1;
+                        }
+                        value.push(c[i]);
+                        i += 1;
+                    }
+                    if i < len {
+                        i += 1;
+                    }
+                }
+                '{' => {
+                    let mut depth = 0;
+                    while i < len {
+                        match c[i] {
+                            '{' => depth += 1,
+                            '}' => {
+                                depth -= 1;
+                                if depth == 0 {
+                                    value.push('}');
+                                    i += 1;
+                                    break;
+                                }
+                            }
+                            '\\' if i + 1 < len => i += 1,
+                            _ => {}
+                        }
+                        value.push(c[i]);
+                        i += 1;
+                    }
+                }
+                '\\' if i + 1 < len => {
+                    value.push(c[i + 1]);
+                    i += 2;
+                }
+                other => {
+                    value.push(other);
+                    i += 1;
+                }
+            }
+        }
+
+        while i < len && is_space(c[i]) {
+            i += 1;
+        }
+
+        if !name.is_empty() {
+            add_option(&mut options, &name, value);
+        }
+    }
+
+    options
+}
+
+/// True if the value is a single balanced `{...}` collection.
+fn is_collection(value: &str) -> bool {
+    if !value.starts_with('{') {
+        return false;
+    }
+    let mut depth = 0;
+    let mut escaped = false;
+    for (pos, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return pos == value.len() - 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+fn encode_value(value: &str) -> String {
+    if is_collection(value) {
+        return value.replace('\\', "\\\\");
+    }
+    if !value.is_empty()
+        && !value.starts_with('{')
+        && !value
+            .chars()
+            .any(|c| is_space(c) || matches!(c, '\'' | '"' | '\\'))
+    {
+        return value.to_owned();
+    }
+    let mut encoded = String::with_capacity(value.len() + 2);
+    encoded.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            encoded.push('\\');
+        }
+        encoded.push(c);
+    }
+    encoded.push('"');
+    encoded
+}
+
+/// Serialize options into a string that [`parse_options`] turns back into the same options.
+pub fn encode_options<'a, I>(options: I) -> String
+where
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    options
+        .into_iter()
+        .map(|(name, value)| format!("{}={}", name, encode_value(value)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Error returned when an option value cannot be parsed into its typed representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidValue;
+
+impl fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid option value")
+    }
+}
+
+macro_rules! keyword_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident => $keyword:literal),+ $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $name {
+            $($variant),+
+        }
+
+        impl $name {
+            pub fn keyword(&self) -> &'static str {
+                match *self {
+                    $($name::$variant => $keyword),+
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = InvalidValue;
+
+            fn from_str(s: &str) -> result::Result<$name, InvalidValue> {
+                match s {
+                    $($keyword => Ok($name::$variant),)+
+                    _ => Err(InvalidValue),
+                }
+            }
+        }
+    };
+}
+
+keyword_enum!(
+    /// The `sides` job attribute.
+    Sides {
+        OneSided => "one-sided",
+        TwoSidedLongEdge => "two-sided-long-edge",
+        TwoSidedShortEdge => "two-sided-short-edge",
+    }
+);
+
+keyword_enum!(
+    /// The `print-color-mode` job attribute.
+    PrintColorMode {
+        Auto => "auto",
+        AutoMonochrome => "auto-monochrome",
+        BiLevel => "bi-level",
+        Color => "color",
+        Highlight => "highlight",
+        Monochrome => "monochrome",
+        ProcessBiLevel => "process-bi-level",
+        ProcessMonochrome => "process-monochrome",
+    }
+);
+
+/// The `print-quality` job attribute, discriminants are the IPP enum values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrintQuality {
+    Draft = 3,
+    Normal = 4,
+    High = 5,
+}
+
+impl FromStr for PrintQuality {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> result::Result<PrintQuality, InvalidValue> {
+        match s {
+            "3" | "draft" => Ok(PrintQuality::Draft),
+            "4" | "normal" => Ok(PrintQuality::Normal),
+            "5" | "high" => Ok(PrintQuality::High),
+            _ => Err(InvalidValue),
+        }
+    }
+}
+
+/// The `orientation-requested` job attribute, discriminants are the IPP enum values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Portrait = 3,
+    Landscape = 4,
+    ReverseLandscape = 5,
+    ReversePortrait = 6,
+    None = 7,
+}
+
+impl FromStr for Orientation {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> result::Result<Orientation, InvalidValue> {
+        match s {
+            "3" | "portrait" => Ok(Orientation::Portrait),
+            "4" | "landscape" => Ok(Orientation::Landscape),
+            "5" | "reverse-landscape" => Ok(Orientation::ReverseLandscape),
+            "6" | "reverse-portrait" => Ok(Orientation::ReversePortrait),
+            "7" | "none" => Ok(Orientation::None),
+            _ => Err(InvalidValue),
+        }
+    }
+}
+
+/// The `job-hold-until` job attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobHoldUntil {
+    NoHold,
+    Indefinite,
+    DayTime,
+    Evening,
+    Night,
+    SecondShift,
+    ThirdShift,
+    Weekend,
+    /// Hold until the given UTC time of day (hours, minutes, seconds).
+    Time(u8, u8, u8),
+}
+
+impl JobHoldUntil {
+    pub fn keyword(&self) -> String {
+        match *self {
+            JobHoldUntil::NoHold => String::from("no-hold"),
+            JobHoldUntil::Indefinite => String::from("indefinite"),
+            JobHoldUntil::DayTime => String::from("day-time"),
+            JobHoldUntil::Evening => String::from("evening"),
+            JobHoldUntil::Night => String::from("night"),
+            JobHoldUntil::SecondShift => String::from("second-shift"),
+            JobHoldUntil::ThirdShift => String::from("third-shift"),
+            JobHoldUntil::Weekend => String::from("weekend"),
+            JobHoldUntil::Time(h, m, s) => format!("{:02}:{:02}:{:02}", h, m, s),
+        }
+    }
+}
+
+impl FromStr for JobHoldUntil {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> result::Result<JobHoldUntil, InvalidValue> {
+        match s {
+            "no-hold" => Ok(JobHoldUntil::NoHold),
+            "indefinite" => Ok(JobHoldUntil::Indefinite),
+            "day-time" => Ok(JobHoldUntil::DayTime),
+            "evening" => Ok(JobHoldUntil::Evening),
+            "night" => Ok(JobHoldUntil::Night),
+            "second-shift" => Ok(JobHoldUntil::SecondShift),
+            "third-shift" => Ok(JobHoldUntil::ThirdShift),
+            "weekend" => Ok(JobHoldUntil::Weekend),
+            _ => {
+                let parts = s
+                    .split(':')
+                    .map(|p| p.parse::<u8>())
+                    .collect::<result::Result<Vec<_>, _>>()
+                    .map_err(|_| InvalidValue)?;
+                let (h, m, s) = match parts[..] {
+                    [h, m] => (h, m, 0),
+                    [h, m, s] => (h, m, s),
+                    _ => return Err(InvalidValue),
+                };
+                if h < 24 && m < 60 && s < 60 {
+                    Ok(JobHoldUntil::Time(h, m, s))
+                } else {
+                    Err(InvalidValue)
+                }
+            }
+        }
+    }
+}
+
+/// The `media` job attribute. The size is known for PWG self-describing names
+/// (`iso_a4_210x297mm`) and common legacy names (`A4`, `Letter`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Media {
+    pub name: String,
+    /// Width and height in hundredths of millimeters.
+    pub size: Option<(u32, u32)>,
+}
+
+const LEGACY_MEDIA: &[(&str, u32, u32)] = &[
+    ("a3", 29700, 42000),
+    ("a4", 21000, 29700),
+    ("a5", 14800, 21000),
+    ("b5", 17600, 25000),
+    ("letter", 21590, 27940),
+    ("legal", 21590, 35560),
+    ("executive", 18415, 26670),
+    ("tabloid", 27940, 43180),
+    ("ledger", 43180, 27940),
+    ("env10", 10478, 24130),
+    ("dl", 11000, 22000),
+];
+
+fn pwg_dimension(s: &str, scale: f64) -> Option<u32> {
+    let value = s.parse::<f64>().ok()?;
+    if value > 0.0 && value < 100_000.0 {
+        Some((value * scale).round() as u32)
+    } else {
+        None
+    }
+}
+
+fn pwg_media_size(name: &str) -> Option<(u32, u32)> {
+    let dims = name.rsplit('_').next()?;
+    let (dims, scale) = if let Some(dims) = dims.strip_suffix("mm") {
+        (dims, 100.0)
+    } else if let Some(dims) = dims.strip_suffix("in") {
+        (dims, 2540.0)
+    } else {
+        return None;
+    };
+    let (w, h) = dims.split_once('x')?;
+    Some((pwg_dimension(w, scale)?, pwg_dimension(h, scale)?))
+}
+
+impl FromStr for Media {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> result::Result<Media, InvalidValue> {
+        let name = s.split(',').next().unwrap_or_default().trim();
+        if name.is_empty() {
+            return Err(InvalidValue);
+        }
+        let size = pwg_media_size(name).or_else(|| {
+            LEGACY_MEDIA
+                .iter()
+                .find(|(legacy, _, _)| legacy.eq_ignore_ascii_case(name))
+                .map(|&(_, w, h)| (w, h))
+        });
+        Ok(Media {
+            name: name.to_owned(),
+            size,
+        })
+    }
+}
+
+/// The `printer-resolution` job attribute such as `600dpi` or `600x1200dpi`,
+/// converted to dots per inch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    pub x: u32,
+    pub y: u32,
+}
+
+impl FromStr for Resolution {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> result::Result<Resolution, InvalidValue> {
+        let s = s.trim().to_ascii_lowercase();
+        let (dims, scale) = if let Some(dims) = s.strip_suffix("dpi") {
+            (dims, 1.0)
+        } else if let Some(dims) = s.strip_suffix("dpcm") {
+            (dims, 2.54)
+        } else {
+            return Err(InvalidValue);
+        };
+        let (x, y) = dims.split_once('x').unwrap_or((dims, dims));
+        let parse = |value: &str| match value.parse::<u32>() {
+            Ok(value @ 1..=100_000) => Ok((value as f64 * scale).round() as u32),
+            _ => Err(InvalidValue),
+        };
+        Ok(Resolution {
+            x: parse(x)?,
+            y: parse(y)?,
+        })
+    }
+}
+
+fn parse_page_ranges(s: &str) -> result::Result<Vec<RangeInclusive<u32>>, InvalidValue> {
+    s.split(',')
+        .map(|range| {
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (start.trim(), end.trim()),
+                None => (range.trim(), range.trim()),
+            };
+            let start = if start.is_empty() {
+                1
+            } else {
+                start.parse().map_err(|_| InvalidValue)?
+            };
+            let end = if end.is_empty() {
+                u32::MAX
+            } else {
+                end.parse().map_err(|_| InvalidValue)?
+            };
+            if start >= 1 && start <= end {
+                Ok(start..=end)
+            } else {
+                Err(InvalidValue)
+            }
+        })
+        .collect()
+}
+
+/// Typed accessors for well-known job options. Each returns `Ok(None)` if the option
+/// is not set and [`BackendError::InvalidOption`] if its value cannot be parsed.
+impl BackendData {
+    fn option_with<T, F>(&self, name: &str, parse: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&str) -> result::Result<T, InvalidValue>,
+    {
+        match self.options.get(name) {
+            Some(value) => parse(value)
+                .map(Some)
+                .map_err(|_| BackendError::InvalidOption {
+                    name: name.to_owned(),
+                    value: value.clone(),
+                }),
+            None => Ok(None),
+        }
+    }
+
+    fn typed_option<T: FromStr<Err = InvalidValue>>(&self, name: &str) -> Result<Option<T>> {
+        self.option_with(name, str::parse)
+    }
+
+    pub fn media(&self) -> Result<Option<Media>> {
+        self.typed_option("media")
+    }
+
+    pub fn sides(&self) -> Result<Option<Sides>> {
+        self.typed_option("sides")
+    }
+
+    pub fn print_color_mode(&self) -> Result<Option<PrintColorMode>> {
+        self.typed_option("print-color-mode")
+    }
+
+    pub fn print_quality(&self) -> Result<Option<PrintQuality>> {
+        self.typed_option("print-quality")
+    }
+
+    /// Number of pages per sheet: 1, 2, 4, 6, 9 or 16.
+    pub fn number_up(&self) -> Result<Option<u32>> {
+        self.option_with("number-up", |s| match s.parse() {
+            Ok(n @ (1 | 2 | 4 | 6 | 9 | 16)) => Ok(n),
+            _ => Err(InvalidValue),
+        })
+    }
+
+    /// Page ranges such as `1-4,7,10-`; an open end is `u32::MAX`.
+    pub fn page_ranges(&self) -> Result<Option<Vec<RangeInclusive<u32>>>> {
+        self.option_with("page-ranges", parse_page_ranges)
+    }
+
+    pub fn orientation_requested(&self) -> Result<Option<Orientation>> {
+        self.typed_option("orientation-requested")
+    }
+
+    /// Job priority from 1 (lowest) to 100 (highest).
+    pub fn job_priority(&self) -> Result<Option<u32>> {
+        self.option_with("job-priority", |s| match s.parse() {
+            Ok(n @ 1..=100) => Ok(n),
+            _ => Err(InvalidValue),
+        })
+    }
+
+    pub fn job_hold_until(&self) -> Result<Option<JobHoldUntil>> {
+        self.typed_option("job-hold-until")
+    }
+
+    /// Resolution from `printer-resolution`, or the `Resolution` PPD option if not set.
+    pub fn printer_resolution(&self) -> Result<Option<Resolution>> {
+        match self.typed_option("printer-resolution")? {
+            Some(resolution) => Ok(Some(resolution)),
+            None => self.typed_option("resolution"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn real_option_strings() {
+        assert_eq!(
+            parse_options(
+                "job-uuid=urn:uuid:4f1d5e0a-7c6b-3a40-62f1-0e1b8c5f7d21 \
+                 job-originating-host-name=localhost date-time-at-creation= \
+                 date-time-at-processing= time-at-creation=1700000000 \
+                 time-at-processing=1700000001 PageSize=A4 number-up=1"
+            ),
+            pairs(&[
+                ("job-uuid", "urn:uuid:4f1d5e0a-7c6b-3a40-62f1-0e1b8c5f7d21"),
+                ("job-originating-host-name", "localhost"),
+                ("date-time-at-creation", ""),
+                ("date-time-at-processing", ""),
+                ("time-at-creation", "1700000000"),
+                ("time-at-processing", "1700000001"),
+                ("PageSize", "A4"),
+                ("number-up", "1"),
+            ])
+        );
+        assert_eq!(
+            parse_options("media=iso_a4_210x297mm,tray-1 sides=two-sided-long-edge collate"),
+            pairs(&[
+                ("media", "iso_a4_210x297mm,tray-1"),
+                ("sides", "two-sided-long-edge"),
+                ("collate", "true"),
+            ])
+        );
+        assert_eq!(parse_options(""), pairs(&[]));
+        assert_eq!(parse_options("   \t\n"), pairs(&[]));
+    }
+
+    #[test]
+    fn quoting_and_escapes() {
+        assert_eq!(
+            parse_options(r#"job-name="My Document" title='it''s' path=C:\\temp\ dir"#),
+            pairs(&[
+                ("job-name", "My Document"),
+                ("title", "its"),
+                ("path", r"C:\temp dir"),
+            ])
+        );
+        assert_eq!(
+            parse_options(r#"a="say \"hi\"" b='x\'y' c="unterminated"#),
+            pairs(&[("a", r#"say "hi""#), ("b", "x'y"), ("c", "unterminated")])
+        );
+        // like cupsParseOptions, whitespace after the = ends the value
+        assert_eq!(
+            parse_options("name= value other=1"),
+            pairs(&[("name", ""), ("value", "true"), ("other", "1")])
+        );
+        // quotes and braces may follow plain text
+        assert_eq!(
+            parse_options(r#"a=foo"bar baz" c=1"#),
+            pairs(&[("a", "foobar baz"), ("c", "1")])
+        );
+        assert_eq!(
+            parse_options(r#"a=x,"y z" b=p{q r}"#),
+            pairs(&[("a", "x,y z"), ("b", "p{q r}")])
+        );
+    }
+
+    #[test]
+    fn empty_names() {
+        assert_eq!(parse_options("no"), pairs(&[]));
+        assert_eq!(parse_options("NO copies=2"), pairs(&[("copies", "2")]));
+        assert_eq!(
+            parse_options("=orphan sides=one-sided ='x y' copies=1"),
+            pairs(&[("sides", "one-sided"), ("copies", "1")])
+        );
+    }
+
+    #[test]
+    fn collections() {
+        assert_eq!(
+            parse_options("media-col={media-size={x-dimension=21000 y-dimension=29700}} copies=2"),
+            pairs(&[
+                (
+                    "media-col",
+                    "{media-size={x-dimension=21000 y-dimension=29700}}"
+                ),
+                ("copies", "2"),
+            ])
+        );
+        assert_eq!(
+            parse_options("{sides=one-sided copies=3}"),
+            pairs(&[("sides", "one-sided"), ("copies", "3")])
+        );
+        assert_eq!(parse_options("col={a=1"), pairs(&[("col", "{a=1")]));
+    }
+
+    #[test]
+    fn negated_and_duplicate_names() {
+        assert_eq!(
+            parse_options("nocollate NoFitToPage fit-to-page"),
+            pairs(&[
+                ("collate", "false"),
+                ("FitToPage", "false"),
+                ("fit-to-page", "true")
+            ])
+        );
+        assert_eq!(
+            parse_options("copies=1 Copies=2 sides=one-sided copies=3"),
+            pairs(&[("copies", "3"), ("sides", "one-sided")])
+        );
+    }
+
+    #[test]
+    fn non_ascii_names() {
+        assert_eq!(parse_options("€"), pairs(&[("€", "true")]));
+        assert_eq!(parse_options("n€"), pairs(&[("n€", "true")]));
+        assert_eq!(parse_options("no€"), pairs(&[("€", "false")]));
+        assert_eq!(parse_options("ñame=välue"), pairs(&[("ñame", "välue")]));
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let options = [
+            ("plain", "value"),
+            ("empty", ""),
+            ("spaces", "two words"),
+            ("quotes", r#"it's "quoted""#),
+            ("backslash", r"C:\temp"),
+            (
+                "collection",
+                "{media-size={x-dimension=21000 y-dimension=29700}}",
+            ),
+            ("brace", "{unbalanced"),
+            ("list", "a,b,c"),
+            ("unicode", "Größe €"),
+        ];
+        let encoded = encode_options(options.iter().copied());
+        let parsed = parse_options(&encoded);
+        assert_eq!(
+            parsed,
+            options
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn typed_values() {
+        assert_eq!(
+            "600x1200dpi".parse::<Resolution>(),
+            Ok(Resolution { x: 600, y: 1200 })
+        );
+        assert_eq!(
+            "118dpcm".parse::<Resolution>(),
+            Ok(Resolution { x: 300, y: 300 })
+        );
+        assert_eq!("600".parse::<Resolution>(), Err(InvalidValue));
+        assert_eq!(
+            parse_page_ranges("1-4,7,10-"),
+            Ok(vec![1..=4, 7..=7, 10..=u32::MAX])
+        );
+        assert_eq!(parse_page_ranges("4-1"), Err(InvalidValue));
+        assert_eq!(
+            "13:05".parse::<JobHoldUntil>(),
+            Ok(JobHoldUntil::Time(13, 5, 0))
+        );
+        assert_eq!(
+            "A4".parse::<Media>().map(|m| m.size),
+            Ok(Some((21000, 29700)))
+        );
+    }
+}
@@ -0,0 +1,414 @@
+//! Printer Job Language: wrapping jobs in `JOB`/`EOJ` commands and parsing the
+//! unsolicited `USTATUS` messages printers send back.
+
+use std::io::{self, Read};
+
+use crate::{format::DocumentFormat, options::Sides, BackendData, Result};
+
+/// Universal exit language sequence, starts and ends PJL jobs.
+pub const UEL: &[u8] = b"\x1b%-12345X";
+
+/// Maximum length of a PJL string value.
+const MAX_STRING_LEN: usize = 80;
+/// Longest `USTATUS` message kept while waiting for its terminating form feed.
+const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Quote a PJL string. Quotes, control and non-ASCII characters are not allowed.
+fn quote(value: &str) -> String {
+    let value = value
+        .chars()
+        .take(MAX_STRING_LEN)
+        .map(|c| match c {
+            '"' => '\'',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '?',
+        })
+        .collect::<String>();
+    format!("\"{}\"", value)
+}
+
+/// PJL personality for a document format.
+pub fn language(format: DocumentFormat) -> Option<&'static str> {
+    match format {
+        DocumentFormat::Pcl => Some("PCL"),
+        DocumentFormat::PostScript => Some("POSTSCRIPT"),
+        DocumentFormat::Pdf => Some("PDF"),
+        _ => None,
+    }
+}
+
+/// PJL job wrapper, built from the job with [`PjlJob::from_data`] or field by field.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PjlJob {
+    /// Job name, reported in the `JOB` and `EOJ` commands and in `USTATUS JOB` messages.
+    pub name: String,
+    pub user: Option<String>,
+    /// Number of copies, not set if `None` or 1.
+    pub copies: Option<u32>,
+    pub sides: Option<Sides>,
+    /// Resolution in dots per inch.
+    pub resolution: Option<u32>,
+    /// Printer language for `ENTER LANGUAGE`, autoswitching is used if not set.
+    pub language: Option<String>,
+    /// Ask for `USTATUS` device, job and page messages for the duration of the job.
+    pub ustatus: bool,
+    /// Additional `SET` commands.
+    pub settings: Vec<(String, String)>,
+}
+
+impl PjlJob {
+    pub fn new(name: &str) -> PjlJob {
+        PjlJob {
+            name: name.to_owned(),
+            ..PjlJob::default()
+        }
+    }
+
+    /// Job wrapper with the title, user, copies, sides and resolution of the job.
+    /// The language is set if the format of the job data is known.
+    pub fn from_data(data: &BackendData) -> Result<PjlJob> {
+        Ok(PjlJob {
+            name: data.title.clone(),
+            user: Some(data.user_name.clone()).filter(|user| !user.is_empty()),
+            copies: Some(data.copies).filter(|&copies| copies > 1),
+            sides: data.sides()?,
+            resolution: data.printer_resolution()?.map(|r| r.x),
+            language: data.document_format()?.and_then(language).map(String::from),
+            ..PjlJob::default()
+        })
+    }
+
+    /// Add a `SET variable=value` command.
+    pub fn set(mut self, variable: &str, value: &str) -> PjlJob {
+        self.settings.push((variable.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Commands sent before the job data.
+    pub fn header(&self) -> Vec<u8> {
+        let mut commands = vec![format!("JOB NAME={}", quote(&self.name))];
+        if self.ustatus {
+            commands.push(String::from("USTATUS DEVICE=ON"));
+            commands.push(String::from("USTATUS JOB=ON"));
+            commands.push(String::from("USTATUS PAGE=ON"));
+        }
+        if let Some(ref user) = self.user {
+            commands.push(format!("SET USERNAME={}", quote(user)));
+        }
+        if let Some(copies) = self.copies.filter(|&copies| copies > 1) {
+            commands.push(format!("SET COPIES={}", copies));
+        }
+        match self.sides {
+            Some(Sides::OneSided) => commands.push(String::from("SET DUPLEX=OFF")),
+            Some(Sides::TwoSidedLongEdge) => {
+                commands.push(String::from("SET DUPLEX=ON"));
+                commands.push(String::from("SET BINDING=LONGEDGE"));
+            }
+            Some(Sides::TwoSidedShortEdge) => {
+                commands.push(String::from("SET DUPLEX=ON"));
+                commands.push(String::from("SET BINDING=SHORTEDGE"));
+            }
+            None => {}
+        }
+        if let Some(resolution) = self.resolution {
+            commands.push(format!("SET RESOLUTION={}", resolution));
+        }
+        for (variable, value) in &self.settings {
+            commands.push(format!("SET {}={}", variable, value));
+        }
+        if let Some(ref language) = self.language {
+            commands.push(format!("ENTER LANGUAGE={}", language));
+        }
+
+        let mut header = UEL.to_vec();
+        header.extend_from_slice(b"@PJL\r\n");
+        for command in commands {
+            header.extend_from_slice(format!("@PJL {}\r\n", command).as_bytes());
+        }
+        header
+    }
+
+    /// Commands sent after the job data.
+    pub fn trailer(&self) -> Vec<u8> {
+        let mut trailer = UEL.to_vec();
+        if self.ustatus {
+            trailer.extend_from_slice(
+                b"@PJL USTATUS DEVICE=OFF\r\n@PJL USTATUS JOB=OFF\r\n@PJL USTATUS PAGE=OFF\r\n",
+            );
+        }
+        trailer.extend_from_slice(format!("@PJL EOJ NAME={}\r\n", quote(&self.name)).as_bytes());
+        trailer.extend_from_slice(UEL);
+        trailer
+    }
+
+    /// Wrap the job data in the header and trailer.
+    pub fn wrap<R: Read>(&self, data: R) -> impl Read {
+        io::Cursor::new(self.header())
+            .chain(data)
+            .chain(io::Cursor::new(self.trailer()))
+    }
+}
+
+/// `USTATUS DEVICE` or `USTATUS TIMED` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceStatus {
+    pub code: u32,
+    pub display: Option<String>,
+    pub online: Option<bool>,
+}
+
+impl DeviceStatus {
+    /// IPP `printer-state-reasons` keyword for common status codes.
+    pub fn state_reason(&self) -> Option<&'static str> {
+        match self.code {
+            10006 | 40038 => Some("toner-low"),
+            40010 => Some("toner-empty"),
+            40019 => Some("output-area-full"),
+            40021 => Some("door-open"),
+            40022 => Some("media-jam"),
+            40079 => Some("offline-report"),
+            41000..=41999 => Some("media-empty"),
+            42000..=42999 => Some("media-jam"),
+            _ => None,
+        }
+    }
+
+    /// Returns true for the ready codes, 10000 to 10002.
+    pub fn is_ready(&self) -> bool {
+        matches!(self.code, 10000..=10002)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobEvent {
+    Start,
+    End,
+}
+
+/// `USTATUS JOB` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobStatus {
+    pub event: JobEvent,
+    pub name: Option<String>,
+    /// Number of pages printed, sent with the end of the job.
+    pub pages: Option<u32>,
+    /// Set if the job did not complete normally, e.g. `CANCELED`.
+    pub result: Option<String>,
+}
+
+/// Unsolicited status message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ustatus {
+    Device(DeviceStatus),
+    Job(JobStatus),
+    /// Number of the page which has just been printed.
+    Page(u32),
+}
+
+impl Ustatus {
+    /// Parse a single message without the terminating form feed.
+    pub fn parse(message: &str) -> Option<Ustatus> {
+        let mut lines = message
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty());
+        let category = lines
+            .next()?
+            .strip_prefix("@PJL")?
+            .split_whitespace()
+            .collect::<Vec<_>>();
+        let category = match category[..] {
+            ["USTATUS", category] => category.to_ascii_uppercase(),
+            _ => return None,
+        };
+
+        match category.as_str() {
+            "DEVICE" | "TIMED" => {
+                let mut status = DeviceStatus {
+                    code: 0,
+                    display: None,
+                    online: None,
+                };
+                for (name, value) in lines.filter_map(|line| line.split_once('=')) {
+                    match name.trim().to_ascii_uppercase().as_str() {
+                        "CODE" => status.code = value.trim().parse().ok()?,
+                        "DISPLAY" => status.display = Some(unquote(value)),
+                        "ONLINE" => status.online = Some(value.trim().eq_ignore_ascii_case("true")),
+                        _ => {}
+                    }
+                }
+                Some(Ustatus::Device(status))
+            }
+            "JOB" => {
+                let event = match lines.next()?.to_ascii_uppercase().as_str() {
+                    "START" => JobEvent::Start,
+                    "END" => JobEvent::End,
+                    _ => return None,
+                };
+                let mut status = JobStatus {
+                    event,
+                    name: None,
+                    pages: None,
+                    result: None,
+                };
+                for (name, value) in lines.filter_map(|line| line.split_once('=')) {
+                    match name.trim().to_ascii_uppercase().as_str() {
+                        "NAME" => status.name = Some(unquote(value)),
+                        "PAGES" => status.pages = value.trim().parse().ok(),
+                        "RESULT" => status.result = Some(value.trim().to_owned()),
+                        _ => {}
+                    }
+                }
+                Some(Ustatus::Job(status))
+            }
+            "PAGE" => lines.next()?.parse().ok().map(Ustatus::Page),
+            _ => None,
+        }
+    }
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+        .to_owned()
+}
+
+/// Collects `USTATUS` messages from back-channel data, which may arrive in
+/// arbitrary pieces. Other PJL responses and non-PJL data are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct UstatusParser {
+    buf: Vec<u8>,
+}
+
+impl UstatusParser {
+    pub fn new() -> UstatusParser {
+        UstatusParser::default()
+    }
+
+    /// Add received data, returning the messages completed by it.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Ustatus> {
+        self.buf.extend_from_slice(data);
+
+        let mut messages = Vec::new();
+        while let Some(end) = self.buf.iter().position(|&b| b == b'\x0c') {
+            let message = self.buf.drain(..=end).collect::<Vec<_>>();
+            let message = String::from_utf8_lossy(&message[..end]);
+            // anything before the PJL prefix is printer output of another kind
+            if let Some(start) = message.find("@PJL") {
+                messages.extend(Ustatus::parse(&message[start..]));
+            }
+        }
+        if self.buf.len() > MAX_MESSAGE_LEN {
+            self.buf.clear();
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_status() {
+        let message = "@PJL USTATUS DEVICE\r\nCODE=10001\r\nDISPLAY=\"Ready\"\r\nONLINE=TRUE\r\n";
+        let status = DeviceStatus {
+            code: 10001,
+            display: Some(String::from("Ready")),
+            online: Some(true),
+        };
+        assert_eq!(
+            Ustatus::parse(message),
+            Some(Ustatus::Device(status.clone()))
+        );
+        assert!(status.is_ready());
+        assert_eq!(status.state_reason(), None);
+
+        let Some(Ustatus::Device(status)) = Ustatus::parse("@PJL USTATUS TIMED\r\nCODE=41203\r\n")
+        else {
+            panic!("not a device status");
+        };
+        assert!(!status.is_ready());
+        assert_eq!(status.state_reason(), Some("media-empty"));
+        assert_eq!(Ustatus::parse("@PJL USTATUS DEVICE\r\nCODE=busy\r\n"), None);
+    }
+
+    #[test]
+    fn state_reasons() {
+        let reason = |code| {
+            DeviceStatus {
+                code,
+                display: None,
+                online: None,
+            }
+            .state_reason()
+        };
+        assert_eq!(reason(40038), Some("toner-low"));
+        assert_eq!(reason(40022), Some("media-jam"));
+        assert_eq!(reason(42101), Some("media-jam"));
+        // unknown codes do not map to a reason
+        assert_eq!(reason(10023), None);
+        assert_eq!(reason(35078), None);
+        assert_eq!(reason(0), None);
+    }
+
+    #[test]
+    fn job_and_page_status() {
+        let message = "@PJL USTATUS JOB\r\nEND\r\nNAME=\"report\"\r\nPAGES=3\r\n";
+        assert_eq!(
+            Ustatus::parse(message),
+            Some(Ustatus::Job(JobStatus {
+                event: JobEvent::End,
+                name: Some(String::from("report")),
+                pages: Some(3),
+                result: None,
+            }))
+        );
+        let message = "@PJL USTATUS JOB\r\nSTART\r\nNAME=\"report\"\r\n";
+        assert!(matches!(
+            Ustatus::parse(message),
+            Some(Ustatus::Job(JobStatus {
+                event: JobEvent::Start,
+                pages: None,
+                ..
+            }))
+        ));
+        assert_eq!(Ustatus::parse("@PJL USTATUS JOB\r\nPAUSED\r\n"), None);
+
+        assert_eq!(
+            Ustatus::parse("@PJL USTATUS PAGE\r\n2\r\n"),
+            Some(Ustatus::Page(2))
+        );
+        assert_eq!(Ustatus::parse("@PJL USTATUS PAGE\r\n"), None);
+        assert_eq!(Ustatus::parse("@PJL INFO STATUS\r\nCODE=10001\r\n"), None);
+    }
+
+    #[test]
+    fn messages_split_across_reads() {
+        let mut parser = UstatusParser::new();
+        assert!(parser.push(b"@PJL USTATUS PA").is_empty());
+        assert!(parser.push(b"GE\r\n1\r\n").is_empty());
+        assert_eq!(
+            parser.push(b"\x0c@PJL USTATUS PAGE\r\n2\r\n\x0c@PJL USTATUS"),
+            [Ustatus::Page(1), Ustatus::Page(2)]
+        );
+        assert_eq!(parser.push(b" PAGE\r\n3\r\n\x0c"), [Ustatus::Page(3)]);
+    }
+
+    #[test]
+    fn garbage_before_pjl() {
+        let mut parser = UstatusParser::new();
+        let data = b"%%[ status: busy ]%%\r\n@PJL USTATUS PAGE\r\n1\r\n\x0c\
+                     no PJL here\x0c@PJL ECHO hello\r\n\x0c";
+        assert_eq!(parser.push(data), [Ustatus::Page(1)]);
+        // an unterminated message is dropped once it gets too long
+        assert!(parser.push(&[b'x'; MAX_MESSAGE_LEN + 1]).is_empty());
+        assert_eq!(
+            parser.push(b"@PJL USTATUS PAGE\r\n2\r\n\x0c"),
+            [Ustatus::Page(2)]
+        );
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    io,
+    os::unix::io::RawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::debug;
+
+use crate::{cupsbackend::current_side_channel, fd, spawn_job_thread};
+
+/// Maximum size of the packet data.
+pub const MAX_DATA: usize = 65535;
+
+const HEADER_SIZE: usize = 4;
+/// How often [`SideChannel::serve`] checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Printer state bits returned by [`Command::GetState`].
+pub const STATE_OFFLINE: u8 = 0x00;
+pub const STATE_ONLINE: u8 = 0x01;
+pub const STATE_BUSY: u8 = 0x02;
+pub const STATE_ERROR: u8 = 0x04;
+pub const STATE_MEDIA_LOW: u8 = 0x10;
+pub const STATE_MEDIA_EMPTY: u8 = 0x20;
+pub const STATE_MARKER_LOW: u8 = 0x40;
+pub const STATE_MARKER_EMPTY: u8 = 0x80;
+
+/// Bidirectional capability values returned by [`Command::GetBidi`].
+pub const BIDI_NOT_SUPPORTED: u8 = 0x00;
+pub const BIDI_SUPPORTED: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    SoftReset = 1,
+    DrainOutput = 2,
+    GetBidi = 3,
+    GetDeviceId = 4,
+    GetState = 5,
+    SnmpGet = 6,
+    SnmpGetNext = 7,
+    GetConnected = 8,
+}
+
+impl Command {
+    pub fn from_u8(value: u8) -> Option<Command> {
+        match value {
+            1 => Some(Command::SoftReset),
+            2 => Some(Command::DrainOutput),
+            3 => Some(Command::GetBidi),
+            4 => Some(Command::GetDeviceId),
+            5 => Some(Command::GetState),
+            6 => Some(Command::SnmpGet),
+            7 => Some(Command::SnmpGetNext),
+            8 => Some(Command::GetConnected),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    None = 0,
+    Ok = 1,
+    IoError = 2,
+    Timeout = 3,
+    NoResponse = 4,
+    BadMessage = 5,
+    TooBig = 6,
+    NotImplemented = 7,
+}
+
+impl Status {
+    pub fn from_u8(value: u8) -> Status {
+        match value {
+            1 => Status::Ok,
+            2 => Status::IoError,
+            3 => Status::Timeout,
+            4 => Status::NoResponse,
+            5 => Status::BadMessage,
+            6 => Status::TooBig,
+            7 => Status::NotImplemented,
+            _ => Status::None,
+        }
+    }
+}
+
+/// Side-channel packet: command, status, 16-bit big-endian data length and data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub command: u8,
+    pub status: Status,
+    pub data: Vec<u8>,
+}
+
+impl Packet {
+    /// Encode the packet. Data longer than [`MAX_DATA`] is not sent,
+    /// the packet is encoded without data and with [`Status::TooBig`] instead.
+    pub fn encode(&self) -> Vec<u8> {
+        let (status, data) = if self.data.len() > MAX_DATA {
+            (Status::TooBig, &[][..])
+        } else {
+            (self.status, &self.data[..])
+        };
+        let mut buf = Vec::with_capacity(HEADER_SIZE + data.len());
+        buf.push(self.command);
+        buf.push(status as u8);
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    /// Decode a packet, returns `None` if the buffer does not contain exactly one packet.
+    pub fn decode(buf: &[u8]) -> Option<Packet> {
+        if buf.len() < HEADER_SIZE {
+            return None;
+        }
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if buf.len() != HEADER_SIZE + len {
+            return None;
+        }
+        Some(Packet {
+            command: buf[0],
+            status: Status::from_u8(buf[1]),
+            data: buf[HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+/// Reply to a side-channel request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: Status,
+    pub data: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: Status, data: Vec<u8>) -> Response {
+        Response { status, data }
+    }
+
+    pub fn ok(data: Vec<u8>) -> Response {
+        Response::new(Status::Ok, data)
+    }
+
+    pub fn not_implemented() -> Response {
+        Response::new(Status::NotImplemented, Vec::new())
+    }
+
+    /// SNMP reply, the data is the OID and the value separated by a NUL byte.
+    pub fn snmp(oid: &str, value: &[u8]) -> Response {
+        let mut data = Vec::with_capacity(oid.len() + 1 + value.len());
+        data.extend_from_slice(oid.as_bytes());
+        data.push(0);
+        data.extend_from_slice(value);
+        Response::ok(data)
+    }
+}
+
+/// Side-channel requests implemented by a backend. The defaults describe
+/// an online, unidirectional device without buffering.
+pub trait SideChannelHandler {
+    fn soft_reset(&mut self) -> Response {
+        Response::not_implemented()
+    }
+
+    /// Wait until all pending output has been sent to the device.
+    fn drain_output(&mut self) -> Response {
+        Response::ok(Vec::new())
+    }
+
+    fn get_bidi(&mut self) -> Response {
+        Response::ok(vec![BIDI_NOT_SUPPORTED])
+    }
+
+    /// IEEE 1284 device ID string.
+    fn get_device_id(&mut self) -> Response {
+        Response::not_implemented()
+    }
+
+    /// Combination of the `STATE_*` bits.
+    fn get_state(&mut self) -> Response {
+        Response::ok(vec![STATE_ONLINE])
+    }
+
+    fn snmp_get(&mut self, _oid: &str) -> Response {
+        Response::not_implemented()
+    }
+
+    fn snmp_get_next(&mut self, _oid: &str) -> Response {
+        Response::not_implemented()
+    }
+
+    fn get_connected(&mut self) -> Response {
+        Response::ok(vec![1])
+    }
+}
+
+/// Handler with the default replies for all requests.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultHandler;
+
+impl SideChannelHandler for DefaultHandler {}
+
+/// Route a request to the handler and build the reply packet.
+pub fn dispatch<H: SideChannelHandler + ?Sized>(handler: &mut H, request: &Packet) -> Packet {
+    let oid = || {
+        let end = request
+            .data
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(request.data.len());
+        String::from_utf8_lossy(&request.data[..end]).into_owned()
+    };
+    let response = match Command::from_u8(request.command) {
+        Some(Command::SoftReset) => handler.soft_reset(),
+        Some(Command::DrainOutput) => handler.drain_output(),
+        Some(Command::GetBidi) => handler.get_bidi(),
+        Some(Command::GetDeviceId) => handler.get_device_id(),
+        Some(Command::GetState) => handler.get_state(),
+        Some(Command::SnmpGet) => handler.snmp_get(&oid()),
+        Some(Command::SnmpGetNext) => handler.snmp_get_next(&oid()),
+        Some(Command::GetConnected) => handler.get_connected(),
+        None => Response::not_implemented(),
+    };
+    Packet {
+        command: request.command,
+        status: response.status,
+        data: response.data,
+    }
+}
+
+/// Side-channel socket between the filters and the backend, passed by CUPS as file descriptor 4.
+#[derive(Debug)]
+pub struct SideChannel {
+    fd: RawFd,
+}
+
+impl SideChannel {
+    pub const FD: RawFd = 4;
+
+    /// Open the side-channel of the job processed by the current thread.
+    /// Returns `None` if the job has none.
+    pub fn open() -> Option<SideChannel> {
+        current_side_channel().map(|fd| SideChannel { fd })
+    }
+
+    /// Use an arbitrary descriptor as the side-channel. The descriptor is not closed on drop.
+    pub fn from_raw_fd(fd: RawFd) -> Option<SideChannel> {
+        if fd::is_open(fd) {
+            Some(SideChannel { fd })
+        } else {
+            None
+        }
+    }
+
+    /// Read one request packet. Returns `Ok(None)` on timeout and fails with
+    /// `ErrorKind::UnexpectedEof` once the filter side has been closed.
+    /// A malformed request is answered with `Status::BadMessage` and skipped.
+    pub fn read_request(&self, timeout: Option<Duration>) -> io::Result<Option<Packet>> {
+        if !fd::poll(self.fd, libc::POLLIN, timeout)? {
+            return Ok(None);
+        }
+        let mut buf = vec![0u8; HEADER_SIZE + MAX_DATA];
+        let n = fd::read(self.fd, &mut buf)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "side-channel closed",
+            ));
+        }
+        match Packet::decode(&buf[..n]) {
+            Some(packet) => Ok(Some(packet)),
+            None => {
+                debug!("Bad side-channel request of {} bytes", n);
+                self.write_packet(&Packet {
+                    command: buf[0],
+                    status: Status::BadMessage,
+                    data: Vec::new(),
+                })?;
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn write_packet(&self, packet: &Packet) -> io::Result<()> {
+        let buf = packet.encode();
+        let mut written = 0;
+        while written < buf.len() {
+            if !fd::poll(self.fd, libc::POLLOUT, None)? {
+                return Err(fd::timed_out("side-channel write"));
+            }
+            match fd::write(self.fd, &buf[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a single pending request, if any arrives within the timeout.
+    /// Returns true if a request was processed.
+    pub fn process<H: SideChannelHandler + ?Sized>(
+        &self,
+        handler: &mut H,
+        timeout: Option<Duration>,
+    ) -> io::Result<bool> {
+        match self.read_request(timeout)? {
+            Some(request) => {
+                let response = dispatch(handler, &request);
+                self.write_packet(&response)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Answer requests on a separate thread until `stop` is set or the filters close the channel.
+    pub fn serve(
+        self,
+        mut handler: Box<dyn SideChannelHandler + Send>,
+        stop: Arc<AtomicBool>,
+    ) -> thread::JoinHandle<()> {
+        spawn_job_thread(move || {
+            while !stop.load(Ordering::SeqCst) {
+                if let Err(e) = self.process(handler.as_mut(), Some(POLL_INTERVAL)) {
+                    if e.kind() != io::ErrorKind::UnexpectedEof {
+                        debug!("Side-channel error: {}", e);
+                    }
+                    break;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::{io::AsRawFd, net::UnixStream},
+    };
+
+    use super::*;
+
+    fn request(filter: &mut UnixStream, command: Command, data: &[u8]) -> Packet {
+        let request = Packet {
+            command: command as u8,
+            status: Status::None,
+            data: data.to_vec(),
+        };
+        filter.write_all(&request.encode()).unwrap();
+        let mut buf = vec![0u8; HEADER_SIZE + MAX_DATA];
+        let n = filter.read(&mut buf).unwrap();
+        Packet::decode(&buf[..n]).unwrap()
+    }
+
+    #[test]
+    fn default_replies() {
+        let mut handler = DefaultHandler;
+        let request = |command: u8| Packet {
+            command,
+            status: Status::None,
+            data: Vec::new(),
+        };
+        let reply = dispatch(&mut handler, &request(Command::GetState as u8));
+        assert_eq!((reply.status, reply.data), (Status::Ok, vec![STATE_ONLINE]));
+        let reply = dispatch(&mut handler, &request(Command::DrainOutput as u8));
+        assert_eq!(reply.status, Status::Ok);
+        let reply = dispatch(&mut handler, &request(Command::GetDeviceId as u8));
+        assert_eq!(reply.status, Status::NotImplemented);
+        let reply = dispatch(&mut handler, &request(99));
+        assert_eq!((reply.command, reply.status), (99, Status::NotImplemented));
+    }
+
+    #[test]
+    fn oversized_data() {
+        let packet = Packet {
+            command: Command::GetDeviceId as u8,
+            status: Status::Ok,
+            data: vec![b'x'; MAX_DATA + 1],
+        };
+        let decoded = Packet::decode(&packet.encode()).unwrap();
+        assert_eq!(decoded.status, Status::TooBig);
+        assert!(decoded.data.is_empty());
+
+        let packet = Packet {
+            data: vec![b'x'; MAX_DATA],
+            ..packet
+        };
+        assert_eq!(Packet::decode(&packet.encode()), Some(packet));
+    }
+
+    #[test]
+    fn serve_until_stopped() {
+        let (mut filter, backend) = UnixStream::pair().unwrap();
+        let side = SideChannel::from_raw_fd(backend.as_raw_fd()).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let server = side.serve(Box::new(DefaultHandler), stop.clone());
+
+        let reply = request(&mut filter, Command::GetBidi, &[]);
+        assert_eq!(reply.command, Command::GetBidi as u8);
+        assert_eq!(reply.data, [BIDI_NOT_SUPPORTED]);
+        let reply = request(&mut filter, Command::SnmpGet, b"1.3.6.1.2.1.1.1.0\0");
+        assert_eq!(reply.status, Status::NotImplemented);
+
+        stop.store(true, Ordering::SeqCst);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn serve_until_closed() {
+        let (filter, backend) = UnixStream::pair().unwrap();
+        let side = SideChannel::from_raw_fd(backend.as_raw_fd()).unwrap();
+        let server = side.serve(Box::new(DefaultHandler), Arc::new(AtomicBool::new(false)));
+        drop(filter);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use crate::{
+    auth::{auth_info_value, AuthInfo},
+    JobId,
+};
+
+/// Message prefix understood by the CUPS scheduler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageLevel {
+    Emergency,
+    Alert,
+    Critical,
+    Error,
+    Warning,
+    Notice,
+    Info,
+    Debug,
+    Debug2,
+}
+
+impl MessageLevel {
+    pub fn prefix(&self) -> &'static str {
+        match *self {
+            MessageLevel::Emergency => "EMERG",
+            MessageLevel::Alert => "ALERT",
+            MessageLevel::Critical => "CRIT",
+            MessageLevel::Error => "ERROR",
+            MessageLevel::Warning => "WARNING",
+            MessageLevel::Notice => "NOTICE",
+            MessageLevel::Info => "INFO",
+            MessageLevel::Debug => "DEBUG",
+            MessageLevel::Debug2 => "DEBUG2",
+        }
+    }
+
+    pub fn from_prefix(prefix: &str) -> Option<MessageLevel> {
+        match prefix {
+            "EMERG" => Some(MessageLevel::Emergency),
+            "ALERT" => Some(MessageLevel::Alert),
+            "CRIT" => Some(MessageLevel::Critical),
+            "ERROR" => Some(MessageLevel::Error),
+            "WARNING" => Some(MessageLevel::Warning),
+            "NOTICE" => Some(MessageLevel::Notice),
+            "INFO" => Some(MessageLevel::Info),
+            "DEBUG" => Some(MessageLevel::Debug),
+            "DEBUG2" => Some(MessageLevel::Debug2),
+            _ => None,
+        }
+    }
+}
+
+impl From<log::Level> for MessageLevel {
+    fn from(level: log::Level) -> MessageLevel {
+        match level {
+            log::Level::Error => MessageLevel::Error,
+            log::Level::Warn => MessageLevel::Warning,
+            log::Level::Info => MessageLevel::Info,
+            log::Level::Debug => MessageLevel::Debug,
+            log::Level::Trace => MessageLevel::Debug2,
+        }
+    }
+}
+
+impl fmt::Display for MessageLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.prefix())
+    }
+}
+
+/// Supply level reported through the `marker-*` printer attributes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Marker {
+    /// Supply name, e.g. "Black Toner".
+    pub name: String,
+    /// Supply color as `#RRGGBB`, or "none".
+    pub color: String,
+    /// Supply type keyword, e.g. "toner" or "ink".
+    pub marker_type: String,
+    /// Level in percent, -1 if unknown.
+    pub level: i32,
+}
+
+/// Writer for the prefixed status lines a backend sends to the scheduler on stderr.
+pub struct StatusWriter<W: Write> {
+    out: W,
+    job_id: Option<JobId>,
+}
+
+impl StatusWriter<io::Stderr> {
+    pub fn stderr() -> StatusWriter<io::Stderr> {
+        StatusWriter::new(io::stderr())
+    }
+}
+
+impl<W: Write> StatusWriter<W> {
+    pub fn new(out: W) -> StatusWriter<W> {
+        StatusWriter { out, job_id: None }
+    }
+
+    /// Tag log messages with the job ID so that concurrent jobs can be told apart.
+    /// State, attribute and page messages are not affected.
+    pub fn with_job_id(mut self, job_id: JobId) -> StatusWriter<W> {
+        self.job_id = Some(job_id);
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn line(&mut self, args: fmt::Arguments) -> io::Result<()> {
+        self.out.write_fmt(args)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()
+    }
+
+    /// Log message with the given level, e.g. `INFO: message`.
+    /// Every line of a multi-line message gets its own prefix.
+    pub fn message(&mut self, level: MessageLevel, message: &str) -> io::Result<()> {
+        for line in message.lines() {
+            match self.job_id {
+                Some(job_id) => self.line(format_args!("{}: [Job {}] {}", level, job_id, line))?,
+                None => self.line(format_args!("{}: {}", level, line))?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn info(&mut self, message: &str) -> io::Result<()> {
+        self.message(MessageLevel::Info, message)
+    }
+
+    pub fn debug(&mut self, message: &str) -> io::Result<()> {
+        self.message(MessageLevel::Debug, message)
+    }
+
+    /// Forward a status line written by a child process. Log messages keep their
+    /// level, state, attribute and page messages are passed through unchanged
+    /// and anything else is reported as `INFO`.
+    pub fn relay(&mut self, line: &str) -> io::Result<()> {
+        if let Some((prefix, rest)) = line.split_once(':') {
+            if let Some(level) = MessageLevel::from_prefix(prefix) {
+                return self.message(level, rest.trim_start());
+            }
+            if let "STATE" | "ATTR" | "PAGE" | "PPD" = prefix {
+                return self.line(format_args!("{}", line));
+            }
+        }
+        self.info(line)
+    }
+
+    /// Add printer-state-reasons keywords: `STATE: +reason,...`
+    pub fn add_state_reasons(&mut self, reasons: &[&str]) -> io::Result<()> {
+        self.line(format_args!("STATE: +{}", reasons.join(",")))
+    }
+
+    /// Remove printer-state-reasons keywords: `STATE: -reason,...`
+    pub fn remove_state_reasons(&mut self, reasons: &[&str]) -> io::Result<()> {
+        self.line(format_args!("STATE: -{}", reasons.join(",")))
+    }
+
+    /// Replace all printer-state-reasons keywords: `STATE: reason,...`
+    pub fn set_state_reasons(&mut self, reasons: &[&str]) -> io::Result<()> {
+        if reasons.is_empty() {
+            self.line(format_args!("STATE: none"))
+        } else {
+            self.line(format_args!("STATE: {}", reasons.join(",")))
+        }
+    }
+
+    /// Set a printer attribute: `ATTR: name=value`. The value must already be encoded.
+    pub fn attr(&mut self, name: &str, value: &str) -> io::Result<()> {
+        self.line(format_args!("ATTR: {}={}", name, value))
+    }
+
+    /// Tell the scheduler which credentials to ask the user for.
+    pub fn auth_info_required(&mut self, auth_info: &[AuthInfo]) -> io::Result<()> {
+        self.attr("auth-info-required", &auth_info_value(auth_info))
+    }
+
+    /// Report supply levels via the marker-colors, marker-levels, marker-names
+    /// and marker-types attributes.
+    pub fn markers(&mut self, markers: &[Marker]) -> io::Result<()> {
+        if markers.is_empty() {
+            return Ok(());
+        }
+        let join =
+            |f: &dyn Fn(&Marker) -> String| markers.iter().map(f).collect::<Vec<_>>().join(",");
+        self.attr("marker-colors", &join(&|m| quote(&m.color)))?;
+        self.attr("marker-levels", &join(&|m| m.level.to_string()))?;
+        self.attr("marker-names", &join(&|m| quote(&m.name)))?;
+        self.attr("marker-types", &join(&|m| quote(&m.marker_type)))
+    }
+
+    /// Page accounting: `PAGE: page copies`
+    pub fn page(&mut self, page: u32, copies: u32) -> io::Result<()> {
+        self.line(format_args!("PAGE: {} {}", page, copies))
+    }
+
+    /// Total page count for the job: `PAGE: total count`
+    pub fn total_pages(&mut self, count: u32) -> io::Result<()> {
+        self.line(format_args!("PAGE: total {}", count))
+    }
+
+    /// Job progress in percent, shown by the web interface.
+    pub fn progress(&mut self, percent: u32) -> io::Result<()> {
+        self.attr("job-media-progress", &percent.min(100).to_string())
+    }
+}
+
+/// Quote an attribute value for ATTR: messages if it contains special characters.
+pub fn quote(value: &str) -> String {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, ',' | '"' | '\'' | '\\'))
+    {
+        return value.to_owned();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}